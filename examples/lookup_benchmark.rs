@@ -1,9 +1,13 @@
-use chash_trie::Trie;
+use chash_trie::store::ChildStore;
+use chash_trie::{ByteTrie, Trie};
 use clap::Parser;
 use rand::prelude::*;
 use rayon::prelude::*;
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering::*},
+        Arc,
+    },
     thread::{available_parallelism, spawn},
     time::Instant,
 };
@@ -18,6 +22,13 @@ struct Opts {
     pub num_lookups: usize,
     #[clap(long)]
     pub max_word_bytes: usize,
+    /// The number of threads removing and re-inserting keys while the
+    /// lookups run.
+    #[clap(long, default_value_t = 0)]
+    pub num_removers: usize,
+    /// Run on a `ByteTrie` rather than a trie with the default store.
+    #[clap(long)]
+    pub byte_trie: bool,
 }
 
 fn main() {
@@ -26,6 +37,8 @@ fn main() {
         num_lookups,
         num_words,
         max_word_bytes,
+        num_removers,
+        byte_trie,
     } = Opts::parse();
     assert!(max_word_bytes >= 1);
 
@@ -44,12 +57,68 @@ fn main() {
             .collect()
     };
 
+    if byte_trie {
+        run(
+            ByteTrie::new().into_inner(),
+            dictionary,
+            num_threads,
+            num_lookups,
+            max_word_bytes,
+            num_removers,
+        );
+    } else {
+        run(
+            Trie::new(),
+            dictionary,
+            num_threads,
+            num_lookups,
+            max_word_bytes,
+            num_removers,
+        );
+    }
+}
+
+fn run<H, C>(
+    trie: Trie<u8, u64, H, C>,
+    dictionary: Vec<(Vec<u8>, u64)>,
+    num_threads: usize,
+    num_lookups: usize,
+    max_word_bytes: usize,
+    num_removers: usize,
+) where
+    H: Send + Sync + 'static,
+    C: ChildStore<u8, H> + Send + Sync + 'static,
+{
     println!("Building the trie concurrently");
-    let trie = Arc::new(Trie::new());
+    let trie = Arc::new(trie);
 
-    dictionary.into_par_iter().for_each(|(key, value)| {
-        trie.pin().insert(key, value);
+    dictionary.par_iter().for_each(|(key, value)| {
+        trie.pin().insert(key.clone(), *value);
     });
+    let dictionary = Arc::new(dictionary);
+
+    let is_done = Arc::new(AtomicBool::new(false));
+    let removers: Vec<_> = (0..num_removers)
+        .map(|_| {
+            let trie = trie.clone();
+            let dictionary = dictionary.clone();
+            let is_done = is_done.clone();
+
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut num_rounds = 0usize;
+
+                while !is_done.load(Relaxed) {
+                    let (key, value) = dictionary.choose(&mut rng).unwrap();
+                    let trie = trie.pin();
+                    trie.remove(key);
+                    trie.insert(key.clone(), *value);
+                    num_rounds += 1;
+                }
+                num_rounds
+            })
+        })
+        .collect();
 
     println!("Run concurrent lookups");
     let since = Instant::now();
@@ -74,5 +143,24 @@ fn main() {
         handle.join().unwrap();
     }
 
-    println!("elapsed {:?}", since.elapsed());
+    let elapsed = since.elapsed();
+    let num_total = (num_threads * num_lookups) as f64;
+    println!(
+        "elapsed {:?}, {:.0} lookups/s",
+        elapsed,
+        num_total / elapsed.as_secs_f64()
+    );
+
+    is_done.store(true, Relaxed);
+    let num_rounds: usize = removers
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
+    if num_removers > 0 {
+        println!(
+            "{} removals and re-insertions, {:.0}/s",
+            num_rounds,
+            num_rounds as f64 / since.elapsed().as_secs_f64()
+        );
+    }
 }
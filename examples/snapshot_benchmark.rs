@@ -0,0 +1,79 @@
+use chash_trie::Trie;
+use clap::Parser;
+use rand::prelude::*;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+struct Opts {
+    /// The number of children of the root.
+    #[clap(long)]
+    pub num_children: u32,
+    /// The number of writes timed after each snapshot, after the first.
+    #[clap(long)]
+    pub num_writes: usize,
+    #[clap(long, default_value_t = 5)]
+    pub num_rounds: usize,
+}
+
+fn main() {
+    let Opts {
+        num_children,
+        num_writes,
+        num_rounds,
+    } = Opts::parse();
+
+    let mut rng = rand::thread_rng();
+
+    println!("Building a trie with {} children at the root", num_children);
+    let trie = Trie::new();
+    {
+        let trie = trie.pin();
+        for i in 0..num_children {
+            trie.insert([i], i as u64);
+        }
+    }
+
+    let since = Instant::now();
+    write(&trie, num_children, num_writes, &mut rng);
+    report("writes without a snapshot", num_writes, since.elapsed());
+
+    for round in 0..num_rounds {
+        let since = Instant::now();
+        let snapshot = trie.snapshot();
+        let snapshot_time = since.elapsed();
+
+        // The first write copies the root, with a link to every child.
+        let since = Instant::now();
+        trie.pin().insert([rng.gen_range(0..num_children), 0], 0);
+        let first_write_time = since.elapsed();
+
+        let since = Instant::now();
+        write(&trie, num_children, num_writes, &mut rng);
+        let writes_time = since.elapsed();
+
+        println!(
+            "round {}: snapshot {:?}, first write {:?}",
+            round, snapshot_time, first_write_time
+        );
+        report("writes after the first", num_writes, writes_time);
+        drop(snapshot);
+    }
+}
+
+/// Replaces the values of random children of the root.
+fn write(trie: &Trie<u32, u64>, num_children: u32, num_writes: usize, rng: &mut ThreadRng) {
+    let trie = trie.pin();
+    for _ in 0..num_writes {
+        let i = rng.gen_range(0..num_children);
+        trie.insert([i], rng.gen());
+    }
+}
+
+fn report(what: &str, num_writes: usize, elapsed: Duration) {
+    println!(
+        "  {} {:?}, {:.0} writes/s",
+        what,
+        elapsed,
+        num_writes as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -1,55 +1,389 @@
-use std::borrow::Borrow;
-use std::hash::{BuildHasher, Hash};
+use std::iter;
+use std::rc::Rc;
 
-use crate::error::Error;
+use crate::error::Retry;
+use crate::iter::Iter;
 use crate::node::Node;
+use crate::state::PinGuard;
+use crate::store::{ChildLookup, ChildStore};
 use crate::GuardedTrie;
 
-#[derive(Debug, Clone)]
-pub struct Entry<'g, S, V, H> {
-    pub(crate) node: &'g Node<S, V, H>,
-    pub(crate) trie: &'g GuardedTrie<'g, S, V, H>,
+type Children<'g, S, V, H, C> = Box<dyn Iterator<Item = (S, Entry<'g, S, V, H, C>)> + 'g>;
+
+/// A cursor on a node of the trie. It remembers the path it was reached
+/// through, along with the segments on the way, so it can walk back up
+/// to the root and tell its key.
+///
+/// In a compressed trie, an entry may sit on a node whose key runs
+/// past the segment it was reached through. Splitting or merging that
+/// node, or one above it, relinks it, after which the writes through
+/// the entry fail as if the node was removed. So do the writes through
+/// the entries reached before a snapshot of the trie is taken, as their
+/// nodes are then shared with the snapshot, and before a transaction
+/// commits a write on the path to them, as those nodes are then
+/// replaced.
+#[derive(Debug)]
+pub struct Entry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub(crate) trie: &'g GuardedTrie<'g, S, V, H, C>,
+    steps: Rc<Steps<'g, S, V, H, C>>,
+    /// The index of the step to this entry in `steps`.
+    index: usize,
+}
+
+/// A stretch of steps along with the index of a step in it.
+type StepAt<'g, S, V, H, C> = (Rc<Steps<'g, S, V, H, C>>, usize);
+
+/// A stretch of the path to an entry. The entries reached by a single
+/// walk down share a stretch, which goes on from the entry the walk
+/// started at.
+#[derive(Debug)]
+struct Steps<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// Where the walk started.
+    parent: Option<StepAt<'g, S, V, H, C>>,
+    steps: Vec<Step<'g, S, V, H, C>>,
+}
+
+#[derive(Debug)]
+struct Step<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    node: &'g Node<S, V, H, C>,
+    /// The segment the node was reached through, or `None` for the
+    /// root.
+    seg: Option<S>,
+    /// The number of times the node was relinked when it was reached.
+    moves: usize,
 }
 
-impl<'g, S, V, H> Entry<'g, S, V, H>
+impl<'g, S, V, H, C> Entry<'g, S, V, H, C>
 where
-    S: Eq + Hash,
-    H: BuildHasher + Clone,
+    S: Send + 'static,
+    V: Send + 'static,
+    C: ChildStore<S, H>,
 {
+    pub(crate) fn root(
+        node: &'g Node<S, V, H, C>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Entry<'g, S, V, H, C> {
+        let step = Step {
+            node,
+            seg: None,
+            moves: node.moves(),
+        };
+        Entry {
+            trie,
+            steps: Rc::new(Steps {
+                parent: None,
+                steps: vec![step],
+            }),
+            index: 0,
+        }
+    }
+
     pub fn get(&self) -> Option<&'g V> {
-        self.node.get(self.trie)
+        self.node().get(self.trie)
     }
 
-    pub fn try_insert(&self, value: V) -> Result<&'g V, Error> {
-        self.node.insert(value, self.trie)
+    /// Sets the value of this entry and returns the replaced value. The
+    /// value is handed back if the node of this entry was removed in
+    /// the meantime.
+    pub fn try_insert(&self, value: V) -> Result<Option<&'g V>, Retry<V>> {
+        let _gate = self.trie.gate();
+        match self.pin_path() {
+            Some(_pins) => Ok(self.node().set_value(value, &self.path(), self.trie)),
+            None => Err(Retry(value)),
+        }
     }
 
-    pub fn child<'a, Q, K>(&self, seg: &Q) -> Option<Entry<'g, S, V, H>>
+    /// Inserts `value` at the child `seg` of this entry and returns the
+    /// replaced value. The value is handed back if the node of this
+    /// entry was removed in the meantime.
+    pub fn insert_child(&self, seg: S, value: V) -> Result<Option<&'g V>, Retry<V>> {
+        self.insert_at(iter::once(seg), value)
+    }
+
+    /// Inserts `value` at `key` relative to this entry and returns the
+    /// replaced value. The value is handed back if the node of this
+    /// entry was removed in the meantime.
+    pub fn insert_at<K>(&self, key: K, value: V) -> Result<Option<&'g V>, Retry<V>>
     where
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        K: IntoIterator<Item = S>,
     {
-        let child_node = self.node.child(seg, self.trie)?;
-        Some(Entry {
-            node: child_node,
-            trie: self.trie,
-        })
+        let _gate = self.trie.gate();
+        match self.pin_path() {
+            Some(pins) => {
+                let node = self.node();
+                Ok(node.insert_at(pins, key, value, &mut self.path(), self.trie))
+            }
+            None => Err(Retry(value)),
+        }
     }
 
-    pub fn find<'a, Q, K>(&'g self, key: K) -> Option<Entry<'g, S, V, H>>
+    /// Removes the value of this entry. If the node turns empty, it is
+    /// unlinked from its parent, and so are the ancestors that turn
+    /// empty in turn.
+    pub fn remove(&self) -> Option<&'g V> {
+        let trie = self.trie;
+        let _gate = trie.gate();
+        let mut pins = match trie.trie.clone_seg {
+            Some(_) => self.pin_path()?,
+            None if self.is_stale() => return None,
+            None => vec![],
+        };
+        let pin = pins.pop();
+        let path = self.path();
+        let result = self.node().remove(pin, None, &path, trie);
+
+        // The pins would keep the ancestors from being unlinked.
+        drop(pins);
+        let (value, is_removed) = result.ok()?;
+        if is_removed {
+            trie.unlink_path(&path);
+        }
+        Some(value)
+    }
+
+    /// Pins the nodes from the root down to this entry, like a writer
+    /// walking down does, unless one of them was removed or relinked
+    /// since the entry was reached, or the node of this entry is shared
+    /// with a snapshot.
+    fn pin_path(&self) -> Option<Vec<PinGuard<'g>>> {
+        if self.is_stale() {
+            return None;
+        }
+
+        let mut steps: Vec<_> = self.steps_up().collect();
+        steps.reverse();
+        steps
+            .into_iter()
+            .map(|step| {
+                let pin = step.node.pin()?;
+                (step.node.moves() == step.moves).then_some(pin)
+            })
+            .collect()
+    }
+
+    /// Tells whether the nodes from the root down to this entry are
+    /// shared with a snapshot taken since the entry was reached, or
+    /// were replaced by the commit of a transaction.
+    fn is_stale(&self) -> bool {
+        self.steps_up().any(|step| step.node.is_stale(self.trie))
+    }
+
+    /// Returns the nodes from the root down to this entry.
+    fn path(&self) -> Vec<&'g Node<S, V, H, C>> {
+        let mut path: Vec<_> = self.steps_up().map(|step| step.node).collect();
+        path.reverse();
+        path
+    }
+
+    /// Gets the child entry at `seg`. The segment is cloned into the
+    /// entry, to tell its key later on.
+    pub fn child<'a, Q>(&self, seg: &Q) -> Option<Entry<'g, S, V, H, C>>
+    where
+        C: ChildLookup<S, Q>,
+        Q: ToOwned<Owned = S> + 'a,
+    {
+        let _gate = self.trie.gate();
+        let (node, moves) = self.node().child_and_moves(seg, self.trie)?;
+        let seg = Some(seg.to_owned());
+        Some(self.descend(vec![Step { node, seg, moves }]))
+    }
+
+    /// Finds the entry at `key` relative to this entry, which may or
+    /// may not hold a value. The segments are cloned into the entry, to
+    /// tell its key later on.
+    pub fn find<'a, Q, K>(&self, key: K) -> Option<Entry<'g, S, V, H, C>>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: ToOwned<Owned = S> + 'a,
+    {
+        let mut key = key.into_iter();
+        let mut node = self.node();
+        let mut steps = vec![];
+        let _gate = self.trie.gate();
+
+        while let Some(seg) = key.next() {
+            let (child_node, moves) = node.child_in(seg, &mut key, self.trie)?;
+            let seg = Some(seg.to_owned());
+            steps.push(Step {
+                node: child_node,
+                seg,
+                moves,
+            });
+            node = child_node;
+        }
+
+        if node.is_removed() {
+            return None;
+        }
+        match steps.is_empty() {
+            true => Some(self.clone()),
+            false => Some(self.descend(steps)),
+        }
+    }
+
+    /// Lists the child entries along with their segments. The segments
+    /// are cloned out of the child map, like the keys yielded by
+    /// [`GuardedTrie::iter_with_keys`].
+    pub fn children(&self) -> Children<'g, S, V, H, C>
+    where
+        S: Clone,
     {
-        let node = self.node.find(key, self.trie)?;
+        let this = self.clone();
+        let _gate = self.trie.gate();
+        let children = self
+            .node()
+            .iter_children(self.trie)
+            .map(move |(seg, node, moves)| {
+                let step = Step {
+                    node,
+                    seg: Some(seg.clone()),
+                    moves,
+                };
+                (seg, this.descend(vec![step]))
+            });
+        Box::new(children)
+    }
+
+    /// Returns the entry this entry was reached from, or `None` for the
+    /// root.
+    pub fn parent(&self) -> Option<Entry<'g, S, V, H, C>> {
+        let (steps, index) = match self.index.checked_sub(1) {
+            Some(index) => (self.steps.clone(), index),
+            None => self.steps.parent.clone()?,
+        };
         Some(Entry {
-            node,
             trie: self.trie,
+            steps,
+            index,
         })
     }
 
+    /// Returns the number of segments in the key of this entry.
+    pub fn depth(&self) -> usize {
+        self.node().depth
+    }
+
+    /// Returns the key this entry was reached through, made of the
+    /// segments recorded on the way down. `None` is returned if a node
+    /// on the path was unlinked or relinked in the meantime.
+    pub fn key(&self) -> Option<Vec<S>>
+    where
+        S: Clone,
+    {
+        let guard = &self.trie.guard;
+        let mut key = vec![];
+        let mut steps = self.steps_up().peekable();
+
+        while let (Some(step), Some(parent)) = (steps.next(), steps.peek()) {
+            let node = step.node;
+            if node.is_removed() || node.moves() != step.moves {
+                return None;
+            }
+
+            // The segments are collected in reverse order.
+            let tail = node.tail(parent.node.depth, guard);
+            key.extend(tail.iter().rev().cloned());
+            key.extend(step.seg.clone());
+        }
+
+        key.reverse();
+        Some(key)
+    }
+
+    /// Iterates over the values in the subtree rooted at this entry.
+    pub fn iter(&self) -> Iter<'g, S, V, H, C> {
+        Iter::new(Some(self.node()), self.trie)
+    }
+
+    /// Iterates over the values in the subtree rooted at this entry
+    /// along with their keys relative to this entry.
+    pub fn iter_with_keys(&self) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        S: Clone,
+    {
+        self.node().iter_with_keys(vec![], self.trie)
+    }
+
     pub fn is_removed(&self) -> bool {
-        self.node.is_removed()
+        self.node().is_removed()
+    }
+
+    fn node(&self) -> &'g Node<S, V, H, C> {
+        self.step().node
+    }
+
+    fn step(&self) -> &Step<'g, S, V, H, C> {
+        &self.steps.steps[self.index]
+    }
+
+    /// Walks the steps from this entry up to the root.
+    fn steps_up(&self) -> impl Iterator<Item = &Step<'g, S, V, H, C>> {
+        let mut at = Some((&*self.steps, self.index));
+        iter::from_fn(move || {
+            let (steps, index) = at?;
+            at = match index.checked_sub(1) {
+                Some(parent_index) => Some((steps, parent_index)),
+                None => steps
+                    .parent
+                    .as_ref()
+                    .map(|(steps, index)| (&**steps, *index)),
+            };
+            Some(&steps.steps[index])
+        })
+    }
+
+    /// Makes the entry at the end of `steps`, which go on from this
+    /// entry.
+    fn descend(&self, steps: Vec<Step<'g, S, V, H, C>>) -> Entry<'g, S, V, H, C> {
+        let index = steps.len() - 1;
+        let steps = Steps {
+            parent: Some((self.steps.clone(), self.index)),
+            steps,
+        };
+        Entry {
+            trie: self.trie,
+            steps: Rc::new(steps),
+            index,
+        }
+    }
+}
+
+impl<'g, S, V, H, C> Clone for Entry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            trie: self.trie,
+            steps: self.steps.clone(),
+            index: self.index,
+        }
+    }
+}
+
+impl<S, V, H, C> Drop for Steps<'_, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    fn drop(&mut self) {
+        // The stretches no entry holds anymore are dropped one by one,
+        // so that the entry of a deep key does not drop them
+        // recursively.
+        let mut parent = self.parent.take();
+        while let Some((steps, _)) = parent {
+            parent = Rc::try_unwrap(steps)
+                .ok()
+                .and_then(|mut steps| steps.parent.take());
+        }
     }
 }
@@ -1,5 +1,75 @@
+use std::fmt;
+
+/// The operation that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    Remove,
+}
+
+/// The error returned by a failed operation. `depth` is the number of
+/// key segments that were walked before the failure.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Error {
-    NotFound,
-    Retry,
+    /// The key holds no value.
+    NotFound { operation: Operation, depth: usize },
+    /// A node on the path was removed concurrently, and the operation
+    /// has to be started over.
+    Retry { operation: Operation, depth: usize },
+}
+
+/// The error returned when an insertion hits a node that was removed
+/// concurrently. It hands back the value to be inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Retry<V>(pub V);
+
+/// The error returned by a failed compare-and-swap. It carries the
+/// value found at the key and gives back the rejected new value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompareAndSwapError<'g, V> {
+    pub current: Option<&'g V>,
+    pub new: V,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { operation, depth } => {
+                write!(f, "{operation} failed: no value found at depth {depth}")
+            }
+            Self::Retry { operation, depth } => write!(
+                f,
+                "{operation} failed: node removed concurrently at depth {depth}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<V> fmt::Display for Retry<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "insert failed: node removed concurrently")
+    }
+}
+
+impl<V: fmt::Debug> std::error::Error for Retry<V> {}
+
+impl<'g, V> fmt::Display for CompareAndSwapError<'g, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.current {
+            Some(_) => write!(f, "compare-and-swap failed: value differs"),
+            None => write!(f, "compare-and-swap failed: no value found"),
+        }
+    }
 }
+
+impl<'g, V: fmt::Debug> std::error::Error for CompareAndSwapError<'g, V> {}
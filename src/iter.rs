@@ -0,0 +1,90 @@
+use crate::node::Node;
+use crate::store::ChildStore;
+use crate::GuardedTrie;
+use std::iter::FusedIterator;
+
+/// An iterator over the values of a trie, or of a subtree of it.
+///
+/// Created by [`GuardedTrie::iter`], [`GuardedTrie::iter_prefix`] and
+/// [`Entry::iter`](crate::Entry::iter). The subtree is walked
+/// depth-first with a stack of the nodes left to visit, so the
+/// iteration allocates nothing beyond the stack itself.
+///
+/// The number of values left is only known on a
+/// [`ReadOnlyTrie`](crate::ReadOnlyTrie), which is never written to.
+/// Elsewhere, [`Iterator::size_hint`] gives no bounds.
+#[derive(Debug)]
+pub struct Iter<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// The nodes left to visit, the next one on top.
+    stack: Vec<&'g Node<S, V, H, C>>,
+    /// The number of values left, if the trie is read-only.
+    remaining: Option<usize>,
+    trie: &'g GuardedTrie<'g, S, V, H, C>,
+}
+
+impl<'g, S, V, H, C> Iter<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// Creates an iterator over the subtree rooted at `node`, if any.
+    pub(crate) fn new(
+        node: Option<&'g Node<S, V, H, C>>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Self {
+        Self {
+            stack: node.into_iter().collect(),
+            remaining: None,
+            trie,
+        }
+    }
+
+    /// Counts the values left, for an iterator over a read-only trie.
+    /// The counts of a trie with writers lag behind them, so the
+    /// number could be off.
+    pub(crate) fn counted(mut self) -> Self {
+        self.remaining = Some(self.stack.iter().map(|node| node.count()).sum());
+        self
+    }
+}
+
+impl<'g, S, V, H, C> Iterator for Iter<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let guard = &self.trie.guard;
+
+        while let Some(node) = self.stack.pop() {
+            // The children are pushed in reverse, so that they are
+            // visited in the order of the store.
+            let start = self.stack.len();
+            node.children.for_each(guard, |_, link| {
+                self.stack
+                    .extend(unsafe { link.load::<Node<S, V, H, C>>(guard).as_ref() });
+            });
+            self.stack[start..].reverse();
+
+            if let Some(value) = node.value(guard) {
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= 1;
+                }
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.remaining {
+            Some(len) => (len, Some(len)),
+            None => (0, None),
+        }
+    }
+}
+
+impl<S, V, H, C> FusedIterator for Iter<'_, S, V, H, C> where C: ChildStore<S, H> {}
@@ -0,0 +1,192 @@
+use std::ptr;
+
+use crate::node::Node;
+use crate::store::ChildStore;
+use crate::GuardedTrie;
+
+/// A view into a single key of a trie, which is either occupied or
+/// vacant.
+#[derive(Debug)]
+pub enum KeyEntry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    Occupied(OccupiedEntry<'g, S, V, H, C>),
+    Vacant(VacantEntry<'g, S, V, H, C>),
+}
+
+#[derive(Debug)]
+pub struct OccupiedEntry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub(crate) key: Vec<S>,
+    pub(crate) node: &'g Node<S, V, H, C>,
+    pub(crate) value: &'g V,
+    pub(crate) trie: &'g GuardedTrie<'g, S, V, H, C>,
+}
+
+#[derive(Debug)]
+pub struct VacantEntry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub(crate) key: Vec<S>,
+    pub(crate) trie: &'g GuardedTrie<'g, S, V, H, C>,
+}
+
+impl<'g, S, V, H, C> KeyEntry<'g, S, V, H, C>
+where
+    S: Send + 'static,
+    V: Send + 'static,
+    C: ChildStore<S, H>,
+{
+    pub fn key(&self) -> &[S] {
+        match self {
+            Self::Occupied(entry) => entry.key(),
+            Self::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value of an occupied entry, or inserts `value` into
+    /// a vacant one.
+    pub fn or_insert(self, value: V) -> &'g V {
+        match self {
+            Self::Occupied(entry) => entry.get(),
+            Self::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    /// Returns the value of an occupied entry, or inserts the result
+    /// of `default` into a vacant one.
+    pub fn or_insert_with<F>(self, default: F) -> &'g V
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Self::Occupied(entry) => entry.get(),
+            Self::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'g V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Replaces the value of an occupied entry with the result of `f`.
+    ///
+    /// If a concurrent writer replaces the value first, `f` is called
+    /// again on the new value. If the value gets removed, the entry
+    /// turns vacant.
+    pub fn and_modify<F>(self, mut f: F) -> Self
+    where
+        F: FnMut(&V) -> V,
+    {
+        let mut entry = match self {
+            Self::Occupied(entry) => entry,
+            Self::Vacant(_) => return self,
+        };
+        let trie = entry.trie;
+        let guard = &trie.guard;
+        let mut pending = None;
+
+        loop {
+            // `f` runs outside of the write, so that it may access the
+            // trie itself.
+            let new_value = match pending.take() {
+                Some(new_value) => new_value,
+                None => f(entry.value),
+            };
+            let _gate = trie.gate();
+
+            // A node shared with a snapshot taken since the entry was
+            // made, or while `f` ran, is copied, and one replaced by a
+            // transaction is found again. The copy holds the same value,
+            // unless it was written to in the meantime, in which case
+            // `f` is called on the new value.
+            if entry.node.is_stale(trie) {
+                let found = trie
+                    .find_for_write(&entry.key)
+                    .and_then(|node| Some((node, node.get(trie)?)));
+                match found {
+                    Some((node, value)) => {
+                        if ptr::eq(value, entry.value) {
+                            pending = Some(new_value);
+                        }
+                        entry.node = node;
+                        entry.value = value;
+                        continue;
+                    }
+                    None => {
+                        break Self::Vacant(VacantEntry {
+                            key: entry.key,
+                            trie,
+                        })
+                    }
+                }
+            }
+
+            match entry
+                .node
+                .compare_exchange_value(entry.value, new_value, guard)
+            {
+                Ok(value) => {
+                    entry.value = value;
+                    break Self::Occupied(entry);
+                }
+                Err((Some(value), _)) => {
+                    entry.value = value;
+                }
+                Err((None, _)) => {
+                    break Self::Vacant(VacantEntry {
+                        key: entry.key,
+                        trie: entry.trie,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<'g, S, V, H, C> OccupiedEntry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub fn key(&self) -> &[S] {
+        &self.key
+    }
+
+    pub fn get(&self) -> &'g V {
+        self.value
+    }
+}
+
+impl<'g, S, V, H, C> VacantEntry<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub fn key(&self) -> &[S] {
+        &self.key
+    }
+
+    pub fn into_key(self) -> Vec<S> {
+        self.key
+    }
+
+    /// Inserts `value` unless a concurrent writer inserts the key
+    /// first, in which case `value` is dropped. Returns the value the
+    /// key ends up with.
+    pub fn insert(self, value: V) -> &'g V
+    where
+        S: Send + 'static,
+        V: Send + 'static,
+    {
+        match self.trie.insert_if_absent(self.key, value) {
+            Ok(value) => value,
+            Err((value, _)) => value,
+        }
+    }
+}
@@ -1,114 +1,583 @@
 mod entry;
 mod error;
-use entry::Entry;
+mod iter;
+mod key_entry;
+pub use entry::Entry;
 pub use error::*;
+pub use iter::Iter;
+pub use key_entry::{KeyEntry, OccupiedEntry, VacantEntry};
 
 mod node;
+mod snapshot;
+mod state;
+pub mod store;
+mod transaction;
+pub use snapshot::{GuardedReadOnlyTrie, ReadOnlyTrie};
+pub use transaction::Transaction;
 
 use crate::node::Node;
+use crate::state::PinGuard;
+use crate::store::{AdaptiveStore, ArtStore, ChildLookup, ChildStore, OrderedStore, SkipListStore};
 use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
-use error::Error;
+use crossbeam::sync::{ShardedLock, ShardedLockReadGuard};
+use once_cell::sync::OnceCell;
 use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hash};
-use std::sync::atomic::Ordering::*;
+use std::iter::empty;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+use std::sync::PoisonError;
+use std::vec;
 
+/// A concurrent trie mapping sequences of segments `S` to values `V`.
+///
+/// The children of each node are kept in a store of type `C`, see
+/// [`store`] for the choices. The stores that hash the segments use
+/// `H` to build their hashers.
+///
+/// Each segment of a key takes a node of its own, unless the trie is
+/// [`compressed`](Trie::compressed).
+///
+/// Readers take no locks of the trie, though the store may take its
+/// own, see [`store`]. Writers take a gate shared, which snapshots and
+/// the commits of transactions take exclusively, and removing a subtree
+/// or splitting a tail waits for the writers below to leave.
+///
+/// The values and segments that a write replaces or removes are dropped
+/// once no pinned thread can see them, maybe on another thread and
+/// after the trie is gone. Writing to a trie thus takes them to be
+/// `Send + 'static`, which rules out e.g. `Rc` values:
+///
+/// ```compile_fail
+/// use chash_trie::Trie;
+/// use std::rc::Rc;
+///
+/// let trie = Trie::new();
+/// trie.pin().insert([1u8], Rc::new(1));
+/// ```
+///
+/// as well as borrowed ones:
+///
+/// ```compile_fail
+/// use chash_trie::Trie;
+///
+/// let value = String::from("borrowed");
+/// let trie = Trie::new();
+/// trie.pin().insert([1u8], value.as_str());
+/// ```
 #[derive(Debug)]
-pub struct Trie<S, V, H = RandomState> {
-    root: Atomic<Node<S, V, H>>,
+pub struct Trie<S, V, H = RandomState, C = AdaptiveStore<S, H>>
+where
+    C: ChildStore<S, H>,
+{
+    root: Atomic<Node<S, V, H, C>>,
     hash_builder: H,
+    len: AtomicUsize,
+    /// Clones segments into the tails of nodes. Set if the trie is
+    /// compressed.
+    clone_seg: Option<fn(&S) -> S>,
+    /// The generation new nodes are created in. It is renewed by every
+    /// snapshot, after which the nodes of older generations are shared.
+    gen: AtomicUsize,
+    /// Held shared by the writers, and exclusively while a snapshot
+    /// renews the generation or a transaction commits.
+    gate: ShardedLock<()>,
+    /// Clones the segments of a node shared with a snapshot into its
+    /// copy. Set once a snapshot is taken.
+    copy_seg: OnceCell<fn(&S) -> S>,
 }
 
+/// A trie keeping the children sorted, see [`SkipListStore`], so that
+/// its keys are iterated over in lexicographic order and can be scanned
+/// by range. The children are not hashed, so the trie keeps no hasher.
+///
+/// Like [`ByteTrie`], it wraps a [`Trie`] to have a constructor of its
+/// own.
+#[derive(Debug)]
+pub struct OrderedTrie<S, V>(Trie<S, V, (), SkipListStore<S>>)
+where
+    S: Ord + Clone + Send + 'static;
+
+/// A trie over bytes, keeping the children of each node like an
+/// adaptive radix tree, see [`ArtStore`]. Children are looked up by
+/// indexing with the byte rather than hashing it, so the trie keeps no
+/// hasher, and the keys are iterated over in order.
+///
+/// It wraps a [`Trie`] and forwards to it, so that snapshots of it are
+/// byte tries in turn. A type alias could not have a constructor of its
+/// own without making untyped calls to [`Trie::new`] ambiguous.
+#[derive(Debug)]
+pub struct ByteTrie<V>(Trie<u8, V, (), ArtStore>);
+
 impl<S, V, H> Trie<S, V, H>
 where
-    S: Eq + Hash,
-    H: BuildHasher + Clone,
+    S: Eq + Hash + Send + 'static,
+    H: BuildHasher + Clone + Send + 'static,
 {
     pub fn with_hasher(hash_builder: H) -> Self {
+        Self::with_store_and_hasher(hash_builder)
+    }
+}
+
+impl<S, V, H, C> Trie<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// Creates a trie with the store picked by its type, e.g.
+    /// `Trie::<u8, V, _, ByteStore>::with_store_and_hasher(hasher)`.
+    pub fn with_store_and_hasher(hash_builder: H) -> Self {
         Self {
             root: Atomic::null(),
             hash_builder,
+            len: AtomicUsize::new(0),
+            clone_seg: None,
+            gen: AtomicUsize::new(0),
+            gate: ShardedLock::new(()),
+            copy_seg: OnceCell::new(),
         }
     }
 
-    pub fn pin(&self) -> GuardedTrie<'_, S, V, H> {
+    /// Turns on path compression, e.g. `Trie::new().compressed()`.
+    ///
+    /// A node of a compressed trie keeps the unbranched stretch of its
+    /// key above it as a tail, rather than a chain of nodes with a
+    /// single child each. This holds for the nodes with children as well
+    /// as for the leaves. Inserting a key that leads off or ends within
+    /// a tail splits it, and a removal that leaves a node with no value
+    /// and a single child merges the child back up. Both happen
+    /// concurrently with the other operations.
+    ///
+    /// The segments of a split or merged tail are cloned.
+    pub fn compressed(mut self) -> Self
+    where
+        S: Clone,
+    {
+        self.clone_seg = Some(S::clone);
+        self
+    }
+
+    /// Tells whether the trie is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.clone_seg.is_some()
+    }
+
+    /// Returns the number of values in the trie. Under concurrent
+    /// writes, the count may briefly be off by the writes in flight,
+    /// as it is raised before a value shows up and lowered after one
+    /// is gone.
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pin(&self) -> GuardedTrie<'_, S, V, H, C> {
         GuardedTrie {
             guard: epoch::pin(),
             trie: self,
         }
     }
+
+    fn copy_seg(&self) -> fn(&S) -> S {
+        *self.copy_seg.get().expect("only snapshots share nodes")
+    }
 }
 
 impl<S, V> Trie<S, V, RandomState>
 where
-    S: Eq + Hash,
+    S: Eq + Hash + Send + 'static,
 {
     pub fn new() -> Self {
         Self::with_hasher(RandomState::default())
     }
 }
 
+impl<S, V, C> Trie<S, V, RandomState, C>
+where
+    C: ChildStore<S, RandomState>,
+{
+    /// Creates a trie with the store picked by its type, e.g.
+    /// `Trie::<u8, V, _, ByteStore>::with_store()`.
+    pub fn with_store() -> Self {
+        Self::with_store_and_hasher(RandomState::default())
+    }
+}
+
 impl<S, V> Default for Trie<S, V, RandomState>
 where
-    S: Eq + Hash,
+    S: Eq + Hash + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, V> OrderedTrie<S, V>
+where
+    S: Ord + Clone + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self(Trie::with_store_and_hasher(()))
+    }
+
+    /// Turns on path compression, see [`Trie::compressed`].
+    pub fn compressed(self) -> Self {
+        Self(self.0.compressed())
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.0.is_compressed()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn pin(&self) -> GuardedTrie<'_, S, V, (), SkipListStore<S>> {
+        self.0.pin()
+    }
+
+    /// Returns the wrapped trie.
+    pub fn into_inner(self) -> Trie<S, V, (), SkipListStore<S>> {
+        self.0
+    }
+
+    /// Takes a snapshot of the trie, see [`Trie::snapshot`].
+    pub fn snapshot(&self) -> Self {
+        Self(self.0.snapshot())
+    }
+
+    /// Takes a snapshot that can only be read, see
+    /// [`Trie::read_only_snapshot`].
+    pub fn read_only_snapshot(&self) -> ReadOnlyTrie<S, V, (), SkipListStore<S>> {
+        self.0.read_only_snapshot()
+    }
+}
+
+impl<S, V> OrderedTrie<S, V>
+where
+    S: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    /// Runs `f` as a transaction, see [`Trie::transaction`].
+    pub fn transaction<F, R>(&self, f: F) -> R
+    where
+        F: FnMut(&mut Transaction<'_, S, V, (), SkipListStore<S>>) -> R,
+    {
+        self.0.transaction(f)
+    }
+}
+
+impl<S, V> Default for OrderedTrie<S, V>
+where
+    S: Ord + Clone + Send + 'static,
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
+impl<V> ByteTrie<V> {
+    pub fn new() -> Self {
+        Self(Trie::with_store_and_hasher(()))
+    }
+
+    /// Turns on path compression, see [`Trie::compressed`].
+    pub fn compressed(self) -> Self {
+        Self(self.0.compressed())
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.0.is_compressed()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn pin(&self) -> GuardedTrie<'_, u8, V, (), ArtStore> {
+        self.0.pin()
+    }
+
+    /// Returns the wrapped trie.
+    pub fn into_inner(self) -> Trie<u8, V, (), ArtStore> {
+        self.0
+    }
+
+    /// Takes a snapshot of the trie, see [`Trie::snapshot`].
+    pub fn snapshot(&self) -> Self {
+        Self(self.0.snapshot())
+    }
+
+    /// Takes a snapshot that can only be read, see
+    /// [`Trie::read_only_snapshot`].
+    pub fn read_only_snapshot(&self) -> ReadOnlyTrie<u8, V, (), ArtStore> {
+        self.0.read_only_snapshot()
+    }
+}
+
+impl<V> ByteTrie<V>
+where
+    V: Clone + Send + 'static,
+{
+    /// Runs `f` as a transaction, see [`Trie::transaction`].
+    pub fn transaction<F, R>(&self, f: F) -> R
+    where
+        F: FnMut(&mut Transaction<'_, u8, V, (), ArtStore>) -> R,
+    {
+        self.0.transaction(f)
+    }
+}
+
+impl<V> Default for ByteTrie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, V, H, C> Drop for Trie<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    fn drop(&mut self) {
+        // No guard can outlive the trie, so the whole tree is torn
+        // down right away, but for the nodes shared with snapshots.
+        unsafe {
+            let root = self.root.load(Relaxed, epoch::unprotected());
+            if !root.is_null() {
+                Node::release_now(root.as_raw());
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct GuardedTrie<'g, S, V, H> {
+pub struct GuardedTrie<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
     guard: Guard,
-    trie: &'g Trie<S, V, H>,
+    trie: &'g Trie<S, V, H, C>,
 }
 
-impl<'g, S, V, H> GuardedTrie<'g, S, V, H>
+impl<'g, S, V, H, C> GuardedTrie<'g, S, V, H, C>
 where
-    S: Eq + Hash,
-    H: BuildHasher + Clone,
+    C: ChildStore<S, H>,
 {
     pub fn get<'a, Q, K>(&self, key: K) -> Option<&V>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
         self.root()?.get_at(key, self)
     }
 
-    pub fn insert<K>(&self, key: K, value: V) -> Option<&V>
+    /// Returns the value of the longest prefix of `key` that is present
+    /// in the trie, along with the length of that prefix.
+    pub fn longest_prefix<'a, Q, K>(&self, key: K) -> Option<(usize, &V)>
     where
-        K: IntoIterator<Item = S> + Clone,
-        V: Clone,
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
-        loop {
-            match self.try_insert(key.clone(), value.clone()) {
-                Ok(value) => break Some(value),
-                Err(Error::NotFound) => break None,
-                Err(Error::Retry) => (),
-            }
+        self.root()?.longest_prefix_at(key, self)
+    }
+
+    /// Returns the values found on the path from the root to `key` in
+    /// root-to-leaf order, each paired with the depth it was found at.
+    pub fn ancestors<'a, Q, K>(&self, key: K) -> vec::IntoIter<(usize, &V)>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let mut values = vec![];
+        if let Some(root) = self.root() {
+            root.ancestors_at(key, self, &mut values);
         }
+        values.into_iter()
+    }
+
+    pub fn iter(&'g self) -> Iter<'g, S, V, H, C> {
+        Iter::new(self.root(), self)
     }
 
-    pub fn try_insert<K>(&self, key: K, value: V) -> Result<&V, Error>
+    /// Iterates over the values along with their keys.
+    ///
+    /// The keys are rebuilt from the child maps, which do not outlive
+    /// their locks, so each key is yielded as an owned path.
+    pub fn iter_with_keys(&'g self) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        S: Clone,
+    {
+        Box::new(
+            self.root()
+                .into_iter()
+                .flat_map(|root| root.iter_with_keys(vec![], self)),
+        )
+    }
+
+    /// Iterates over the values whose keys start with `prefix`.
+    pub fn iter_prefix<'a, Q, K>(&'g self, prefix: K) -> Iter<'g, S, V, H, C>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let node = self.root().and_then(|root| root.cover(prefix, self));
+        Iter::new(node, self)
+    }
+
+    /// Returns the number of values whose keys start with `prefix`,
+    /// without walking the subtree. Like [`Trie::len`], the count may
+    /// briefly lag behind concurrent writes.
+    pub fn count_prefix<'a, Q, K>(&self, prefix: K) -> usize
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let node = self.root().and_then(|root| root.cover(prefix, self));
+        node.map_or(0, |node| node.count())
+    }
+
+    pub fn keys(&'g self) -> Box<dyn Iterator<Item = Vec<S>> + 'g>
+    where
+        S: Clone,
+    {
+        Box::new(self.iter_with_keys().map(|(key, _)| key))
+    }
+
+    fn root(&self) -> Option<&Node<S, V, H, C>> {
+        let shared = self.trie.root.load_consume(&self.guard);
+        unsafe { shared.as_ref() }
+    }
+
+    /// Returns the generation the writers create nodes in.
+    pub(crate) fn gen(&self) -> usize {
+        self.trie.gen.load(Acquire)
+    }
+
+    /// Holds off snapshots while a writer is in flight. The writer has
+    /// to hold the gate from the time it reads the generation until
+    /// its last write, so that it never writes to a node shared with a
+    /// snapshot.
+    pub(crate) fn gate(&self) -> ShardedLockReadGuard<'_, ()> {
+        self.trie
+            .gate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// The writers leave the values, nodes and segments they replace or
+// remove to be dropped by the epoch-based collector, which may run the
+// drops on another thread, and after the trie is gone.
+impl<'g, S, V, H, C> GuardedTrie<'g, S, V, H, C>
+where
+    S: Send + 'static,
+    V: Send + 'static,
+    C: ChildStore<S, H>,
+{
+    /// Inserts `value` at `key` and returns the replaced value.
+    pub fn insert<K>(&self, key: K, value: V) -> Option<&V>
+    where
+        K: IntoIterator<Item = S>,
+    {
+        let _gate = self.gate();
+        let (root, pin) = self.pin_or_create_root();
+        root.insert_at(vec![pin], key, value, &mut vec![root], self)
+    }
+
+    /// Inserts `value` at `key` and returns the replaced value.
+    ///
+    /// This has the signature of [`Entry::try_insert`], which fails once
+    /// the node of the entry is removed or relinked. Removed nodes on
+    /// the path are replaced rather than reported, so inserting through
+    /// the trie never has to be retried, and this never fails.
+    pub fn try_insert<K>(&self, key: K, value: V) -> Result<Option<&V>, Retry<V>>
     where
         K: IntoIterator<Item = S>,
     {
-        self.get_or_create_root().insert_at(key, value, self)
+        Ok(self.insert(key, value))
+    }
+
+    /// Inserts `value` unless the key holds a value already. On
+    /// failure, the current value is returned along with `value`.
+    pub fn insert_if_absent<K>(&self, key: K, value: V) -> Result<&V, (&V, V)>
+    where
+        K: IntoIterator<Item = S>,
+    {
+        let _gate = self.gate();
+        let (root, pin) = self.pin_or_create_root();
+        let (mut path, mut pins) = (vec![root], vec![pin]);
+        let node = root.find_or_create_at(&mut pins, key, &mut path, self);
+        node.insert_if_absent(value, &path, self)
+    }
+
+    /// Replaces the value at `key` with `new_value` if the current
+    /// value equals `expected`, and returns the replaced value.
+    pub fn compare_and_swap<'a, Q, K>(
+        &self,
+        key: K,
+        expected: &V,
+        new_value: V,
+    ) -> Result<&V, CompareAndSwapError<'_, V>>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+        V: PartialEq,
+    {
+        let _gate = self.gate();
+        let node = self.find_for_write(key);
+        let mut current = node.and_then(|node| node.get(self));
+        let mut new_value = new_value;
+
+        loop {
+            let (node, value) = match (node, current) {
+                (Some(node), Some(value)) if value == expected => (node, value),
+                _ => {
+                    break Err(CompareAndSwapError {
+                        current,
+                        new: new_value,
+                    })
+                }
+            };
+
+            match node.compare_exchange_value(value, new_value, &self.guard) {
+                Ok(_) => break Ok(value),
+                Err((value, rejected)) => {
+                    current = value;
+                    new_value = rejected;
+                }
+            }
+        }
     }
 
     pub fn remove<'a, Q, K>(&self, key: K) -> Option<&V>
     where
         K: IntoIterator<Item = &'a Q> + Clone,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
         loop {
             match self.try_remove(key.clone()) {
                 Ok(value) => break Some(value),
-                Err(Error::NotFound) => break None,
-                Err(Error::Retry) => {}
+                Err(Error::NotFound { .. }) => break None,
+                Err(Error::Retry { .. }) => {}
             }
         }
     }
@@ -116,42 +585,289 @@ where
     pub fn try_remove<'a, Q, K>(&self, key: K) -> Result<&V, Error>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let _gate = self.gate();
+        self.try_remove_value(key, None)
+    }
+
+    /// Applies `f` to the value at `key` and stores the outcome, where
+    /// `None` stands for no value. This can insert, replace or remove
+    /// the value. If a concurrent writer changes the value in between,
+    /// `f` is called again on the new value.
+    ///
+    /// Returns the value that was replaced or removed.
+    ///
+    /// `f` runs outside of the write, so it may access the trie, take
+    /// snapshots of it, or run transactions on it. The key is walked
+    /// again for every call of `f`.
+    pub fn update<K, F>(&self, key: K, mut f: F) -> Option<&V>
+    where
+        K: IntoIterator<Item = S> + Clone,
+        F: FnMut(Option<&V>) -> Option<V>,
     {
-        let (value, is_child_removed) = self.root().ok_or(Error::NotFound)?.remove_at(key, self)?;
+        let segs: Vec<S> = key.clone().into_iter().collect();
+        let guard = &self.guard;
+
+        loop {
+            let node = {
+                let _gate = self.gate();
+                self.find_for_write(&segs)
+            };
+            let current = node.and_then(|node| node.get(self));
+
+            match (node, current, f(current)) {
+                (_, None, None) => break None,
+                (_, None, Some(new_value)) => {
+                    if self.insert_if_absent(key.clone(), new_value).is_ok() {
+                        break None;
+                    }
+                }
+                (Some(node), Some(value), outcome) => {
+                    let _gate = self.gate();
+
+                    // A snapshot taken while `f` ran shares the node,
+                    // which is copied before it is written to, and a
+                    // transaction committed meanwhile may have replaced
+                    // it.
+                    if node.is_stale(self) {
+                        continue;
+                    }
+
+                    let is_written = match outcome {
+                        Some(new_value) => {
+                            node.compare_exchange_value(value, new_value, guard).is_ok()
+                        }
+                        None => self.try_remove_value(&segs, Some(value)).is_ok(),
+                    };
+
+                    if is_written {
+                        break Some(value);
+                    }
+                }
+                (None, Some(_), _) => unreachable!(),
+            }
+        }
+    }
+
+    fn try_remove_value<'a, 't, Q, K>(
+        &'t self,
+        key: K,
+        expected: Option<&'t V>,
+    ) -> Result<&'t V, Error>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.try_remove_with(key, false, |node, pin, path| {
+            node.remove(pin, expected, path, self)
+        })
+    }
+
+    /// Removes all values whose keys start with `prefix`, and returns
+    /// the number of removed values.
+    ///
+    /// The node at `prefix` is marked removed along with its subtree,
+    /// and then unlinked from its parent at once. In a compressed trie,
+    /// the prefix may end within the tail of that node.
+    ///
+    /// Marking a node waits for the writers pinning it to leave, so the
+    /// removal blocks while insertions into the subtree are in flight.
+    pub fn remove_prefix<'a, Q, K>(&self, prefix: K) -> usize
+    where
+        K: IntoIterator<Item = &'a Q> + Clone,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        loop {
+            let _gate = self.gate();
+            let result = self.try_remove_with(prefix.clone(), true, |node, pin, path| {
+                Ok((node.remove_subtree(pin, path, self), true))
+            });
+
+            match result {
+                Ok(num_values) => break num_values,
+                Err(Error::NotFound { .. }) => break 0,
+                Err(Error::Retry { .. }) => {}
+            }
+        }
+    }
+
+    fn try_remove_with<'a, 't, Q, K, R, F>(
+        &'t self,
+        key: K,
+        prefix: bool,
+        remove: F,
+    ) -> Result<R, Error>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+        F: FnOnce(
+            &'t Node<S, V, H, C>,
+            Option<PinGuard<'t>>,
+            &[&'t Node<S, V, H, C>],
+        ) -> Result<(R, bool), Error>,
+    {
+        let root_shared = self.root_for_write();
+        let root = unsafe { root_shared.as_ref() }.ok_or(Error::NotFound {
+            operation: Operation::Remove,
+            depth: 0,
+        })?;
+        // The root is never relinked.
+        let (value, is_child_removed) =
+            root.remove_at(key, root.moves(), prefix, &mut vec![], self, remove)?;
 
         if is_child_removed {
-            self.trie.root.store(Shared::null(), Release);
+            self.unset_root(root_shared);
         }
 
         Ok(value)
     }
 
-    pub fn iter(&'g self) -> Box<dyn Iterator<Item = &'g V> + 'g> {
-        Box::new(self.root().into_iter().flat_map(|root| root.iter(self)))
+    /// Unlinks the last node of `path`, which was marked removed, from
+    /// its parent, and so on up for the ancestors that turn empty in
+    /// turn. `path` holds the nodes from the root down.
+    fn unlink_path(&self, path: &[&Node<S, V, H, C>]) {
+        for (i, node) in path.iter().enumerate().rev() {
+            match i.checked_sub(1) {
+                Some(parent) if path[parent].unlink_child(node, &self.guard) => {}
+                Some(_) => break,
+                None => self.unset_root(Shared::from(*node as *const Node<S, V, H, C>)),
+            }
+        }
     }
 
-    pub fn entry<'a, Q, K>(&'g self, key: K) -> Option<Entry<'g, S, V, H>>
+    /// Unsets the root if it is still `root_shared`, which was marked
+    /// removed.
+    fn unset_root(&self, root_shared: Shared<'_, Node<S, V, H, C>>) {
+        let result = self.trie.root.compare_exchange(
+            root_shared,
+            Shared::null(),
+            AcqRel,
+            Acquire,
+            &self.guard,
+        );
+
+        if result.is_ok() {
+            unsafe {
+                Node::release(root_shared, &self.guard);
+            }
+        }
+    }
+
+    /// Gets the entry for `key` for in-place manipulation.
+    ///
+    /// The key is occupied if it holds a value at the time of the call.
+    pub fn entry<K>(&'g self, key: K) -> KeyEntry<'g, S, V, H, C>
+    where
+        K: IntoIterator<Item = S>,
+    {
+        let key: Vec<S> = key.into_iter().collect();
+        let occupied = self
+            .root()
+            .and_then(|root| root.find(&key, self))
+            .and_then(|node| Some((node, node.get(self)?)));
+
+        match occupied {
+            Some((node, value)) => KeyEntry::Occupied(OccupiedEntry {
+                key,
+                node,
+                value,
+                trie: self,
+            }),
+            None => KeyEntry::Vacant(VacantEntry { key, trie: self }),
+        }
+    }
+
+    /// Finds the node at `key`, which may or may not hold a value.
+    ///
+    /// The entries are written through, so the nodes on the way that
+    /// are shared with a snapshot are copied, like by a writer. The
+    /// segments are cloned into the entries, to tell their keys.
+    pub fn find<'a, Q, K>(&'g self, key: K) -> Option<Entry<'g, S, V, H, C>>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: ToOwned<Owned = S> + 'a,
     {
-        let node = self.root()?.find(key, self)?;
-        Some(Entry { node, trie: self })
+        let root = {
+            let _gate = self.gate();
+            unsafe { self.root_for_write().as_ref() }?
+        };
+        Entry::root(root, self).find(key)
     }
 
-    fn root(&self) -> Option<&Node<S, V, H>> {
-        let shared = self.trie.root.load_consume(&self.guard);
-        unsafe { shared.as_ref() }
+    /// Loads the root for a writer. A root shared with a snapshot is
+    /// copied first.
+    fn root_for_write(&self) -> Shared<'_, Node<S, V, H, C>> {
+        let guard = &self.guard;
+
+        loop {
+            let shared = self.trie.root.load_consume(guard);
+            let root = match unsafe { shared.as_ref() } {
+                Some(root) if root.gen() != self.gen() => root,
+                _ => break shared,
+            };
+
+            let copy = Owned::new(root.copy(self)).into_shared(guard);
+            match self
+                .trie
+                .root
+                .compare_exchange(shared, copy, AcqRel, Acquire, guard)
+            {
+                Ok(_) => unsafe { Node::release(shared, guard) },
+                Err(_) => unsafe { drop(copy.into_owned()) },
+            }
+        }
     }
 
-    fn get_or_create_root(&self) -> &Node<S, V, H> {
-        match self.root() {
+    /// Finds the node at `key` for a writer, see
+    /// [`Node::find_for_write`].
+    fn find_for_write<'a, Q, K>(&self, key: K) -> Option<&Node<S, V, H, C>>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let root = unsafe { self.root_for_write().as_ref() }?;
+        root.find_for_write(key, self)
+    }
+
+    /// Gets the root, creating it if necessary, and pins it against
+    /// being marked removed.
+    fn pin_or_create_root(&self) -> (&Node<S, V, H, C>, PinGuard<'_>) {
+        loop {
+            let root = self.get_or_create_root();
+            if let Some(pin) = root.pin() {
+                break (root, pin);
+            }
+
+            // The root is removed but not unset by its remover yet.
+            let shared = Shared::from(root as *const Node<S, V, H, C>);
+            let result = self.trie.root.compare_exchange(
+                shared,
+                Owned::new(Node::new(self.gen())),
+                AcqRel,
+                Acquire,
+                &self.guard,
+            );
+
+            if result.is_ok() {
+                unsafe {
+                    Node::release(shared, &self.guard);
+                }
+            }
+        }
+    }
+
+    fn get_or_create_root(&self) -> &Node<S, V, H, C> {
+        match unsafe { self.root_for_write().as_ref() } {
             Some(root) => root,
             None => {
-                let new_shared = Owned::new(Node::new()).into_shared(&self.guard);
+                let new_shared = Owned::new(Node::new(self.gen())).into_shared(&self.guard);
                 let result = self.trie.root.compare_exchange(
                     Shared::null(),
                     new_shared,
@@ -169,3 +885,56 @@ where
         }
     }
 }
+
+impl<'g, S, V, H, C> GuardedTrie<'g, S, V, H, C>
+where
+    S: Ord + Clone,
+    C: OrderedStore<S, H>,
+{
+    /// Iterates over the values along with their keys, starting at the
+    /// first key not less than `key`. The keys come in lexicographic
+    /// order.
+    pub fn iter_from<'a, K>(&'g self, key: K) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        K: IntoIterator<Item = &'a S>,
+        S: 'a,
+    {
+        let from = key.into_iter().cloned().collect();
+        match self.root() {
+            Some(root) => root.iter_from(vec![], from, self),
+            None => Box::new(empty()),
+        }
+    }
+
+    /// Iterates over the values whose keys fall in `range`, along with
+    /// their keys, in lexicographic order.
+    ///
+    /// The scan stops at the end of the range, so a page of keys can
+    /// be listed by starting the next range after the last key seen.
+    pub fn range<K, R>(&'g self, range: R) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        K: Borrow<[S]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let iter = match range.start_bound() {
+            Bound::Included(start) => self.iter_from(start.borrow()),
+            Bound::Excluded(start) => {
+                let start = start.borrow().to_vec();
+                let iter = self.iter_from(&start);
+                Box::new(iter.skip_while(move |(key, _)| *key == start))
+            }
+            Bound::Unbounded => self.iter_with_keys(),
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(end) => Bound::Included(end.borrow().to_vec()),
+            Bound::Excluded(end) => Bound::Excluded(end.borrow().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Box::new(iter.take_while(move |(key, _)| match &end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        }))
+    }
+}
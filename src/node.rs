@@ -1,72 +1,339 @@
-use crate::{error::Error, GuardedTrie};
-use crossbeam::epoch::{Atomic, Guard, Owned, Shared};
-use dashmap::DashMap;
-use once_cell::sync::Lazy;
-use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
-use std::hash::{BuildHasher, Hash};
-use std::iter;
-use std::sync::atomic::Ordering::*;
-use std::sync::RwLock;
-use std::thread::available_parallelism;
-
-type ChildMap<S, V, H = RandomState> = DashMap<S, Atomic<Node<S, V, H>>, H>;
+use crate::{
+    error::{Error, Operation},
+    state::{PinGuard, State},
+    store::{ChildLookup, ChildStore, Link, OrderedStore},
+    GuardedTrie,
+};
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
+use crossbeam::utils::Backoff;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
 
 #[derive(Debug)]
-pub(crate) struct Node<S, V, H> {
-    pub(crate) children: Atomic<ChildMap<S, V, H>>,
-    pub(crate) value: Atomic<V>,
-    pub(crate) is_deleted: RwLock<bool>,
+pub(crate) struct Node<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub(crate) children: C,
+    pub(crate) value: Atomic<Value<V>>,
+    pub(crate) state: State,
+    /// The number of values in the subtree rooted at this node.
+    pub(crate) count: AtomicUsize,
+    /// The number of segments in the key of this node.
+    pub(crate) depth: usize,
+    /// The segments a node of a compressed trie keeps of its key. The
+    /// last `depth - parent depth - 1` of them follow the segment the
+    /// node is linked under, and are called its tail. Null if empty.
+    /// The run only grows, so that it holds the tail below any parent
+    /// the node had.
+    run: Atomic<Vec<S>>,
+    /// Bumped whenever the node is relinked under another parent.
+    moves: AtomicUsize,
+    /// Bumped after the value or a link to a child changes, so that a
+    /// transaction can tell whether a copy it made is still up to date.
+    version: AtomicUsize,
+    /// The generation of the trie the node was created in. A node of an
+    /// older generation is shared with a snapshot, and is copied rather
+    /// than written to. A transaction moves the nodes it commits over
+    /// to the generation of the trie.
+    gen: AtomicUsize,
+    /// The number of links owning the node. There are several once the
+    /// node is shared with a snapshot.
+    refs: AtomicUsize,
+    marker: PhantomData<fn() -> (S, H)>,
+}
+
+/// The value of a node, along with the number of nodes holding it. A
+/// copy of a node shared with a snapshot holds the same value as the
+/// original, rather than a clone of it.
+#[derive(Debug)]
+#[repr(C)]
+pub(crate) struct Value<V> {
+    /// Comes first, so that a reference to it handed out by the trie
+    /// points to the whole.
+    value: V,
+    refs: AtomicUsize,
+}
+
+/// A node along with the number of times it was relinked.
+type WithMoves<'g, S, V, H, C> = (&'g Node<S, V, H, C>, usize);
+
+/// A node along with its key.
+pub(crate) type WithKey<'g, S, V, H, C> = (&'g Node<S, V, H, C>, Vec<S>);
+
+/// A node left to visit, along with the segment and the tail it adds
+/// to the key of its parent, and the length of that key. The segment is
+/// `None` for the node the visit starts at.
+type Pending<'g, S, V, H, C> = (&'g Node<S, V, H, C>, Option<(S, &'g [S])>, usize);
+
+/// Where a key leads to below a node.
+enum Descent<'g, N> {
+    /// To the child, whose tail the key matched in full.
+    Child(&'g N),
+    /// Into the tail of the child, where the key ended.
+    Within(&'g N),
+}
+
+impl<'g, N> Descent<'g, N> {
+    fn child(self) -> Option<&'g N> {
+        match self {
+            Self::Child(node) => Some(node),
+            Self::Within(_) => None,
+        }
+    }
+}
+
+/// What a writer finds at a link on its way down.
+enum Found<'g, N> {
+    /// The child, pinned, whose tail matches the key.
+    Child(&'g N, PinGuard<'g>),
+    /// The child, whose tail is to be split after the given number of
+    /// segments.
+    Split(Shared<'g, N>, usize),
+    /// A frozen child, which is relinked shortly.
+    Frozen,
 }
 
-impl<S, V, H> Node<S, V, H>
+impl<S, V, H, C> Node<S, V, H, C>
 where
-    S: Eq + Hash,
-    H: BuildHasher + Clone,
+    C: ChildStore<S, H>,
 {
-    pub fn new() -> Self {
+    /// Creates a root in the generation `gen`.
+    pub fn new(gen: usize) -> Self {
+        Self::with_run(gen, 0, vec![])
+    }
+
+    /// Creates a node at `depth` in the generation `gen`, which keeps
+    /// `run` as its tail.
+    fn with_run(gen: usize, depth: usize, run: Vec<S>) -> Self {
         Self {
-            children: Atomic::null(),
+            children: C::new(),
             value: Atomic::null(),
-            is_deleted: RwLock::new(false),
+            state: State::new(),
+            count: AtomicUsize::new(0),
+            depth,
+            run: match run.is_empty() {
+                true => Atomic::null(),
+                false => Atomic::new(run),
+            },
+            moves: AtomicUsize::new(0),
+            version: AtomicUsize::new(0),
+            gen: AtomicUsize::new(gen),
+            refs: AtomicUsize::new(1),
+            marker: PhantomData,
+        }
+    }
+
+    /// Copies the node into the current generation of the trie, for a
+    /// writer that found it shared with a snapshot. The copy holds the
+    /// same value and links to the same children, which are shared in
+    /// turn. It takes O(n) for n children.
+    pub fn copy(&self, trie: &GuardedTrie<'_, S, V, H, C>) -> Self {
+        let guard = &trie.guard;
+        let clone_seg = trie.trie.copy_seg();
+        let hash_builder = &trie.trie.hash_builder;
+        let run = unsafe { self.run.load_consume(guard).as_ref() };
+        let run = run.map_or(vec![], |run| run.iter().map(clone_seg).collect());
+
+        let node = Self::with_run(trie.gen(), self.depth, run);
+        node.count.store(self.count(), Relaxed);
+        let value = self.value.load_consume(guard);
+        if let Some(held) = unsafe { value.as_ref() } {
+            held.refs.fetch_add(1, Relaxed);
+            node.value.store(value, Relaxed);
+        }
+
+        self.children.for_each(guard, |seg, link| {
+            let child_shared = link.load::<Self>(guard);
+            if let Some(child) = unsafe { child_shared.as_ref() } {
+                child.share();
+                node.children
+                    .with_link(clone_seg(seg), hash_builder, guard, |link| {
+                        link.compare_exchange(Shared::null(), child_shared, guard)
+                            .ok()
+                    });
+            }
+        });
+        node
+    }
+
+    /// Adds a link owning the node, which then shares it.
+    pub fn share(&self) {
+        self.refs.fetch_add(1, Relaxed);
+    }
+
+    /// Returns the generation the node is in.
+    pub fn gen(&self) -> usize {
+        self.gen.load(Relaxed)
+    }
+
+    /// Moves the node over to the generation `gen`. The caller holds
+    /// the gate of the trie exclusively.
+    pub fn set_gen(&self, gen: usize) {
+        self.gen.store(gen, Relaxed);
+    }
+
+    /// Returns the number of times the value or a link to a child of
+    /// the node changed.
+    pub fn version(&self) -> usize {
+        self.version.load(Acquire)
+    }
+
+    /// Bumps the version once the value or a link to a child changed.
+    fn touch(&self) {
+        self.version.fetch_add(1, Release);
+    }
+
+    /// Tells whether a writer holding on to the node has to find it
+    /// again, as it is shared with a snapshot taken since, or was
+    /// replaced by the commit of a transaction and retired.
+    pub fn is_stale(&self, trie: &GuardedTrie<'_, S, V, H, C>) -> bool {
+        self.gen() != trie.gen() || self.state.is_frozen()
+    }
+
+    /// Replaces the child `shared` at `link` of this node with a copy,
+    /// if the child is shared with a snapshot. Returns whether it was,
+    /// in which case the link is to be read again.
+    fn renew<'g>(
+        &self,
+        link: &Link,
+        shared: Shared<'g, Self>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> bool {
+        let guard = &trie.guard;
+        let node = match unsafe { shared.as_ref() } {
+            Some(node) if node.gen() != trie.gen() => node,
+            _ => return false,
+        };
+
+        let copy = Owned::new(node.copy(trie)).into_shared(guard);
+        match link.compare_exchange(shared, copy, guard) {
+            Ok(()) => unsafe {
+                self.touch();
+                Self::release(shared, guard)
+            },
+            Err(_) => unsafe { drop(copy.into_owned()) },
         }
+        true
+    }
+
+    /// Drops the link owning the unlinked node once no pinned thread
+    /// can observe it, and the node along with it unless a snapshot
+    /// still links to it.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have unlinked the node, and must do so only
+    /// once per link.
+    pub unsafe fn release(shared: Shared<'_, Self>, guard: &Guard) {
+        let node = shared.as_raw();
+        guard.defer_unchecked(move || Self::release_now(node));
     }
 
-    pub fn get_at<'a, 'g, Q, K>(&self, key: K, trie: &'g GuardedTrie<'g, S, V, H>) -> Option<&'g V>
+    /// Drops a link owning the node right away, like [`Self::release`].
+    ///
+    /// # Safety
+    ///
+    /// No other thread may observe the node through the dropped link.
+    pub unsafe fn release_now(node: *const Self) {
+        if (*node).refs.fetch_sub(1, AcqRel) == 1 {
+            drop(Owned::from_raw(node as *mut Self));
+        }
+    }
+
+    pub fn get_at<'a, 'g, Q, K>(
+        &self,
+        key: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g V>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
         let mut key = key.into_iter();
-        let guard = &trie.guard;
+        let mut node = self;
 
-        // Get the value
-        let value = match key.next() {
-            Some(seg) => {
-                let child_node = {
-                    let is_deleted = self.is_deleted.read().unwrap();
-                    if *is_deleted {
-                        return None;
-                    }
+        while let Some(seg) = key.next() {
+            node = node.descend(seg, &mut key, trie)?.child()?;
+        }
 
-                    let entry = self.children(guard)?.get(seg)?;
-                    let atomic = entry.value();
-                    load_atomic(atomic, guard)?
-                };
-                child_node.get_at(key, trie)?
-            }
-            None => self.get(trie)?,
-        };
+        node.get(trie)
+    }
 
-        Some(value)
+    /// Finds the deepest node on the path of `key` that holds a value,
+    /// and returns the value along with the depth of that node.
+    pub fn longest_prefix_at<'a, 'g, Q, K>(
+        &self,
+        key: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<(usize, &'g V)>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let mut longest = None;
+        self.for_each_ancestor_at(key, trie, |depth, value| {
+            longest = Some((depth, value));
+        });
+        longest
+    }
+
+    /// Collects the values on the path of `key` in root-to-leaf order,
+    /// each paired with the depth of its node.
+    pub fn ancestors_at<'a, 'g, Q, K>(
+        &self,
+        key: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+        values: &mut Vec<(usize, &'g V)>,
+    ) where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.for_each_ancestor_at(key, trie, |depth, value| {
+            values.push((depth, value));
+        });
+    }
+
+    /// Calls `f` on the values on the path of `key` in root-to-leaf
+    /// order, along with the depths of their nodes.
+    fn for_each_ancestor_at<'a, 'g, Q, K, F>(
+        &self,
+        key: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+        mut f: F,
+    ) where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+        F: FnMut(usize, &'g V),
+    {
+        let mut key = key.into_iter();
+        let guard = &trie.guard;
+        let mut node = self;
+
+        while !node.is_removed() {
+            if let Some(value) = node.value(guard) {
+                f(node.depth, value);
+            }
+            node = match key.next() {
+                Some(seg) => match node.descend(seg, &mut key, trie).and_then(Descent::child) {
+                    Some(child_node) => child_node,
+                    None => break,
+                },
+                None => break,
+            };
+        }
     }
 
-    pub fn get<'g>(&self, trie: &'g GuardedTrie<'g, S, V, H>) -> Option<&'g V> {
+    pub fn get<'g>(&self, trie: &'g GuardedTrie<'g, S, V, H, C>) -> Option<&'g V> {
         let guard = &trie.guard;
 
-        let is_deleted = self.is_deleted.read().unwrap();
-        if *is_deleted {
+        if self.is_removed() {
             return None;
         }
 
@@ -76,315 +343,1359 @@ where
     pub fn child<'a, 'g, Q>(
         &self,
         seg: &Q,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> Option<&'g Node<S, V, H>>
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g Node<S, V, H, C>>
     where
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
         let guard = &trie.guard;
 
-        let is_deleted = self.is_deleted.read().unwrap();
-        if *is_deleted {
+        if self.is_removed() {
             return None;
         }
 
-        let entry = self.children(guard)?.get(seg)?;
-        let atomic = entry.value();
-        let child_node = load_atomic(atomic, guard)?;
+        let child_shared = self.child_shared(seg, guard);
+        unsafe { child_shared.as_ref() }
+    }
+
+    /// Gets the child at `seg` along with the number of times it was
+    /// relinked, read while the child stayed at `seg`. The child is
+    /// loaded for a writer, see [`Self::child_for_write`], as entries
+    /// are written through.
+    pub fn child_and_moves<'a, 'g, Q>(
+        &self,
+        seg: &Q,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<WithMoves<'g, S, V, H, C>>
+    where
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        if self.is_removed() {
+            return None;
+        }
+
+        load_with_moves(|| self.child_for_write(seg, trie))
+    }
+
+    /// Gets the child at `seg` like [`Self::child_and_moves`], if its
+    /// tail matches the next segments of `key`.
+    pub fn child_in<'a, 'g, Q, I>(
+        &self,
+        seg: &Q,
+        key: &mut I,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<WithMoves<'g, S, V, H, C>>
+    where
+        I: Iterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let (child_node, moves) = self.child_and_moves(seg, trie)?;
+        let tail = child_node.tail(self.depth, &trie.guard);
+        let is_match = tail.iter().all(|run_seg| {
+            key.next()
+                .is_some_and(|seg| <C as ChildLookup<S, Q>>::matches(run_seg, seg))
+        });
+        is_match.then_some((child_node, moves))
+    }
 
-        Some(child_node)
+    /// Follows the child at `seg` and matches its tail against the
+    /// next segments of `key`.
+    fn descend<'a, 'g, Q, I>(
+        &self,
+        seg: &Q,
+        key: &mut I,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<Descent<'g, Self>>
+    where
+        I: Iterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let child_node = self.child(seg, trie)?;
+        for run_seg in child_node.tail(self.depth, &trie.guard) {
+            match key.next() {
+                Some(seg) if <C as ChildLookup<S, Q>>::matches(run_seg, seg) => {}
+                Some(_) => return None,
+                None => return Some(Descent::Within(child_node)),
+            }
+        }
+        Some(Descent::Child(child_node))
+    }
+
+    /// Returns the segments of the key that follow the one linking
+    /// this node to a parent at `parent_depth`.
+    pub fn tail<'g>(&self, parent_depth: usize, guard: &'g Guard) -> &'g [S] {
+        let len = self.depth - parent_depth - 1;
+        if len == 0 {
+            return &[];
+        }
+
+        let run = unsafe { self.run.load_consume(guard).deref() };
+        &run[run.len() - len..]
+    }
+
+    /// Returns the number of times the node was relinked.
+    pub fn moves(&self) -> usize {
+        self.moves.load(Acquire)
+    }
+
+    /// Loads the link to the child at `seg`. The link is null if there
+    /// is no child, or if the child is being unlinked.
+    fn child_shared<'g, Q>(&self, seg: &Q, guard: &'g Guard) -> Shared<'g, Self>
+    where
+        C: ChildLookup<S, Q>,
+    {
+        self.children
+            .get(seg, guard, |link| link.load(guard))
+            .unwrap_or_else(Shared::null)
+    }
+
+    /// Loads the link to the child at `seg` like [`Self::child_shared`],
+    /// for a writer. A child shared with a snapshot is copied first,
+    /// unless this node is shared as well.
+    fn child_for_write<'g, Q>(
+        &self,
+        seg: &Q,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Shared<'g, Self>
+    where
+        C: ChildLookup<S, Q>,
+    {
+        let guard = &trie.guard;
+        if self.gen() != trie.gen() {
+            return self.child_shared(seg, guard);
+        }
+
+        self.children
+            .get(seg, guard, |link| loop {
+                let shared = link.load(guard);
+                if !self.renew(link, shared, trie) {
+                    break shared;
+                }
+            })
+            .unwrap_or_else(Shared::null)
+    }
+
+    /// Lists the children along with their segments and the number of
+    /// times they were relinked. The children are loaded for a writer,
+    /// like in [`Self::child_and_moves`].
+    pub fn iter_children<'g>(
+        &'g self,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> impl Iterator<Item = (S, &'g Node<S, V, H, C>, usize)> + 'g
+    where
+        S: Clone,
+    {
+        let guard = &trie.guard;
+        let is_shared = self.gen() != trie.gen();
+        let mut children = vec![];
+        self.children.for_each(guard, |seg, link| {
+            while !is_shared && self.renew(link, link.load(guard), trie) {}
+            let child = load_with_moves(|| link.load::<Self>(guard));
+            children.extend(child.map(|(node, moves)| (seg.clone(), node, moves)));
+        });
+        children.into_iter()
+    }
+
+    /// Collects the child nodes.
+    fn child_nodes<'g>(&self, guard: &'g Guard) -> Vec<&'g Self> {
+        let mut nodes = vec![];
+        self.children.for_each(guard, |_, link| {
+            nodes.extend(unsafe { link.load::<Self>(guard).as_ref() });
+        });
+        nodes
     }
 
     pub fn find<'a, 'g, Q, K>(
         &'g self,
         key: K,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> Option<&'g Node<S, V, H>>
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g Node<S, V, H, C>>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
     {
         let mut key = key.into_iter();
-        let guard = &trie.guard;
+        let mut node = self;
 
-        // Get the value
-        let node = match key.next() {
-            Some(seg) => {
-                let child_node = {
-                    let is_deleted = self.is_deleted.read().unwrap();
-                    if *is_deleted {
-                        return None;
-                    }
+        while let Some(seg) = key.next() {
+            node = node.descend(seg, &mut key, trie)?.child()?;
+        }
 
-                    let entry = self.children(guard)?.get(seg)?;
-                    let atomic = entry.value();
-                    load_atomic(atomic, guard)?
-                };
-                child_node.find(key, trie)?
+        (!node.is_removed()).then_some(node)
+    }
+
+    /// Finds the node at `key` like [`Self::find`], for a writer. The
+    /// nodes on the way that are shared with a snapshot are copied.
+    pub fn find_for_write<'a, 'g, Q, K>(
+        &'g self,
+        key: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g Node<S, V, H, C>>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let mut key = key.into_iter();
+        let mut node = self;
+
+        while let Some(seg) = key.next() {
+            if node.is_removed() {
+                return None;
             }
-            None => {
-                let is_deleted = self.is_deleted.read().unwrap();
-                if *is_deleted {
-                    return None;
-                }
 
-                self
+            let child_node = unsafe { node.child_for_write(seg, trie).as_ref() }?;
+            for run_seg in child_node.tail(node.depth, &trie.guard) {
+                match key.next() {
+                    Some(seg) if <C as ChildLookup<S, Q>>::matches(run_seg, seg) => {}
+                    _ => return None,
+                }
             }
-        };
+            node = child_node;
+        }
 
-        Some(node)
+        (!node.is_removed()).then_some(node)
     }
 
+    /// Finds the node whose subtree holds the keys starting with
+    /// `prefix`. The prefix may end within the tail of that node.
+    pub fn cover<'a, 'g, Q, K>(
+        &'g self,
+        prefix: K,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g Node<S, V, H, C>>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        let mut prefix = prefix.into_iter();
+        let mut node = self;
+
+        // A prefix ending within a tail leaves nothing more to match.
+        while let Some(seg) = prefix.next() {
+            node = match node.descend(seg, &mut prefix, trie)? {
+                Descent::Child(child_node) | Descent::Within(child_node) => child_node,
+            };
+        }
+
+        (!node.is_removed()).then_some(node)
+    }
+
+    /// Collects the nodes that a writer to `key` walks through from
+    /// this node on, along with their keys below this node, without
+    /// copying any. The path ends at a missing child, or at a child
+    /// whose tail leads off or past the key, which a writer still walks
+    /// into to split it or to find the key missing.
+    pub fn path_to<'g>(&'g self, key: &[S], guard: &'g Guard) -> Vec<WithKey<'g, S, V, H, C>>
+    where
+        S: Clone,
+    {
+        let mut path = vec![(self, vec![])];
+        let mut node = self;
+        let mut rest = key;
+
+        while let Some((seg, after)) = rest.split_first() {
+            let child_node = match unsafe { node.child_shared(seg, guard).as_ref() } {
+                Some(child_node) => child_node,
+                None => break,
+            };
+            let tail = child_node.tail(node.depth, guard);
+            let mut child_key = key[..key.len() - after.len()].to_vec();
+            child_key.extend_from_slice(tail);
+            path.push((child_node, child_key));
+
+            let is_match = tail.len() <= after.len()
+                && tail
+                    .iter()
+                    .zip(after)
+                    .all(|(run_seg, seg)| <C as ChildLookup<S, S>>::matches(run_seg, seg));
+            if !is_match {
+                break;
+            }
+            rest = &after[tail.len()..];
+            node = child_node;
+        }
+        path
+    }
+
+    /// Inserts `value` at `key` below this node and returns the
+    /// replaced value. `pins` holds the pins on the nodes of `path`,
+    /// which holds the nodes from the root down to this node.
     pub fn insert_at<'g, K>(
-        &self,
+        &'g self,
+        mut pins: Vec<PinGuard<'g>>,
         key: K,
         value: V,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> Result<&'g V, Error>
+        path: &mut Vec<&'g Node<S, V, H, C>>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g V>
     where
         K: IntoIterator<Item = S>,
     {
-        let mut key = key.into_iter();
+        let node = self.find_or_create_at(&mut pins, key, path, trie);
+        node.set_value(value, path, trie)
+    }
+
+    /// Walks down `key` from this node and creates the missing nodes on
+    /// the way. `pins` holds the pins on the nodes of `path`, which
+    /// holds the nodes from the root down to this node. The nodes
+    /// walked are appended to `path`, and the pins on them to `pins`.
+    ///
+    /// A child is pinned before the writer goes on below it, and the
+    /// pins are to be held until the counts on the path are updated.
+    /// The returned node is still linked into the trie until then, and
+    /// no node is split in above it, which would miss the update.
+    ///
+    /// In a compressed trie, a new child keeps the rest of the key as
+    /// its tail, and a child whose tail leads off the key is split.
+    pub fn find_or_create_at<'g, K>(
+        &'g self,
+        pins: &mut Vec<PinGuard<'g>>,
+        key: K,
+        path: &mut Vec<&'g Node<S, V, H, C>>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> &'g Node<S, V, H, C>
+    where
+        K: IntoIterator<Item = S>,
+    {
+        let mut node = self;
+
+        // Only a compressed trie looks ahead in the key, to match the
+        // tails of the children against it.
+        let clone_seg = match trie.trie.clone_seg {
+            Some(clone_seg) => clone_seg,
+            None => {
+                for seg in key {
+                    let (child_node, child_pin) = match node.find_or_create_child(seg, &[], trie) {
+                        Found::Child(child_node, child_pin) => (child_node, child_pin),
+                        Found::Split(..) | Found::Frozen => {
+                            unreachable!("only compressed tries have tails")
+                        }
+                    };
+                    node = child_node;
+                    path.push(node);
+                    pins.push(child_pin);
+                }
+                return node;
+            }
+        };
+
+        let backoff = Backoff::new();
+
+        // The segments are popped off the back.
+        let mut key: Vec<S> = key.into_iter().collect();
+        key.reverse();
+
+        while let Some(seg) = key.pop() {
+            // A child is split or waited for outside of the store, which
+            // takes the segment, so the segment is kept to find the
+            // child again.
+            let seg_again = clone_seg(&seg);
+            let (child_node, child_pin) = match node.find_or_create_child(seg, &key, trie) {
+                Found::Child(child_node, child_pin) => (child_node, child_pin),
+                Found::Split(child_shared, len) => {
+                    node.split(&seg_again, child_shared, len, trie);
+                    key.push(seg_again);
+                    continue;
+                }
+                // The link is read again once the child is relinked.
+                Found::Frozen => {
+                    backoff.snooze();
+                    key.push(seg_again);
+                    continue;
+                }
+            };
+
+            // The tail of the child matched the key.
+            let parent_depth = node.depth;
+            node = child_node;
+            path.push(node);
+            pins.push(child_pin);
+            key.truncate(key.len() + parent_depth + 1 - node.depth);
+        }
+
+        node
+    }
+
+    /// Finds the child at `seg` for a writer with `rest` of its key
+    /// left, in reverse order, and pins it. A missing or removed child
+    /// is replaced with a new one.
+    fn find_or_create_child<'g>(
+        &'g self,
+        seg: S,
+        rest: &[S],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Found<'g, Self> {
         let guard = &trie.guard;
+        let hash_builder = &trie.trie.hash_builder;
 
-        match key.next() {
-            Some(seg) => {
-                let child_node = {
-                    let is_deleted = self.is_deleted.read().unwrap();
-                    if *is_deleted {
-                        return Err(Error::Retry);
+        self.children
+            .with_link(seg, hash_builder, guard, |link| loop {
+                let shared = link.load::<Self>(guard);
+                if shared == Link::dead() {
+                    break None;
+                }
+
+                // A child shared with a snapshot is copied first.
+                if self.renew(link, shared, trie) {
+                    continue;
+                }
+
+                if let Some(child_node) = unsafe { shared.as_ref() } {
+                    if let Some(child_pin) = child_node.pin() {
+                        // The child may have been relinked before it was
+                        // pinned.
+                        if link.load::<Self>(guard) != shared {
+                            continue;
+                        }
+
+                        match child_node.split_len(self.depth, rest, guard) {
+                            None => break Some(Found::Child(child_node, child_pin)),
+                            Some(len) => break Some(Found::Split(shared, len)),
+                        }
                     }
-                    let entry = self
-                        .get_or_create_children(trie)
-                        .entry(seg)
-                        .or_insert_with(|| Atomic::new(Node::new()));
-                    let atomic = entry.value();
-                    load_atomic(atomic, guard).ok_or(Error::NotFound)?
-                };
 
-                child_node.insert_at(key, value, trie)
+                    if child_node.state.is_frozen() {
+                        break Some(Found::Frozen);
+                    }
+                }
+
+                // The link is vacant, or the child is removed but not
+                // unlinked by its remover yet. Replacing a removed child
+                // makes the unlink fail, so the child is reclaimed here
+                // instead.
+                let new = Owned::new(self.new_child(rest, trie)).into_shared(guard);
+                match link.compare_exchange(shared, new, guard) {
+                    Ok(()) if shared.is_null() => self.touch(),
+                    Ok(()) => unsafe {
+                        self.touch();
+                        Self::release(shared, guard)
+                    },
+                    Err(_) => unsafe { drop(new.into_owned()) },
+                }
+            })
+    }
+
+    /// Creates a child for a writer with `rest` of its key left, in
+    /// reverse order, after the segment of the child. In a compressed
+    /// trie, the child keeps the rest as its tail.
+    fn new_child(&self, rest: &[S], trie: &GuardedTrie<'_, S, V, H, C>) -> Self {
+        let gen = trie.gen();
+        match trie.trie.clone_seg {
+            Some(clone_seg) => {
+                let run = rest.iter().rev().map(clone_seg).collect();
+                Self::with_run(gen, self.depth + 1 + rest.len(), run)
             }
-            None => self.insert(value, trie),
+            None => Self::with_run(gen, self.depth + 1, vec![]),
+        }
+    }
+
+    /// Tells how many segments of its tail this node keeps below a
+    /// split, for a writer with `rest` of its key left, in reverse
+    /// order, after the segment linking this node to a parent at
+    /// `parent_depth`. That is where the key leads off or ends within
+    /// the tail. Returns `None` if the tail matches the key, so that
+    /// the writer can go on at this node.
+    fn split_len(&self, parent_depth: usize, rest: &[S], guard: &Guard) -> Option<usize> {
+        let mut rest = rest.iter().rev();
+        self.tail(parent_depth, guard).iter().position(|run_seg| {
+            !rest
+                .next()
+                .is_some_and(|seg| <C as ChildLookup<S, S>>::matches(run_seg, seg))
+        })
+    }
+
+    /// Splits the tail of the child `child_shared` at `seg` of this
+    /// node, by putting a new node between the two. The new node keeps
+    /// the first `len` segments of the tail, and links to the child
+    /// under the next one. The child keeps the rest of its tail, along
+    /// with its children.
+    ///
+    /// The caller must hold the pin on this node. The child is frozen
+    /// while it is relinked, which waits for the writers in its subtree
+    /// to leave, as their paths miss the new node. Nothing is done if
+    /// the link changes in the meantime. The store is only entered to
+    /// look up the link, so that the writers waited for do not wait on
+    /// the store in turn.
+    fn split<'g>(
+        &self,
+        seg: &S,
+        child_shared: Shared<'g, Self>,
+        len: usize,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) {
+        let guard = &trie.guard;
+        let child = unsafe { child_shared.deref() };
+        let backoff = Backoff::new();
+
+        let _freeze = loop {
+            if self.child_shared(seg, guard) != child_shared {
+                return;
+            }
+
+            match child.state.freeze() {
+                Some(freeze) => break freeze,
+                None if child.is_removed() => return,
+                None => backoff.snooze(),
+            }
+        };
+
+        // A writer may have removed the subtree on its way out.
+        if child.is_removed() || self.child_shared(seg, guard) != child_shared {
+            return;
         }
+
+        let clone_seg = trie
+            .trie
+            .clone_seg
+            .expect("only compressed tries have tails");
+        let hash_builder = &trie.trie.hash_builder;
+        let tail = child.tail(self.depth, guard);
+
+        // The new node holds the values of the child.
+        let run = tail[..len].iter().map(clone_seg).collect();
+        let branch = Self::with_run(trie.gen(), self.depth + 1 + len, run);
+        branch.count.store(child.count(), Relaxed);
+        branch
+            .children
+            .with_link(clone_seg(&tail[len]), hash_builder, guard, |link| {
+                link.compare_exchange(Shared::null(), child_shared, guard)
+                    .ok()
+            });
+        let branch = Owned::new(branch).into_shared(guard);
+
+        // Nothing but this split replaces a frozen child.
+        let result = self.children.get(seg, guard, |link| {
+            link.compare_exchange(child_shared, branch, guard)
+        });
+        assert!(matches!(result, Some(Ok(()))));
+        self.touch();
+        child.moves.fetch_add(1, AcqRel);
     }
 
-    pub fn insert<'g>(&self, value: V, trie: &'g GuardedTrie<'g, S, V, H>) -> Result<&'g V, Error> {
+    /// Merges the child `child_shared` at `seg` away if it holds no
+    /// value and a single child. The grandchild then takes the place of
+    /// the child, with the tail of the child and the segment of the
+    /// grandchild prepended to its tail.
+    ///
+    /// Both nodes are frozen while the grandchild is relinked. The
+    /// merge is given up if either has writers, which also covers the
+    /// writers further down, as they keep their paths pinned.
+    fn try_merge<'g, Q>(
+        &self,
+        seg: &Q,
+        child_shared: Shared<'g, Self>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) where
+        C: ChildLookup<S, Q>,
+    {
         let guard = &trie.guard;
-        let is_deleted = self.is_deleted.read().unwrap();
-        if *is_deleted {
-            return Err(Error::Retry);
+        let clone_seg = match trie.trie.clone_seg {
+            Some(clone_seg) => clone_seg,
+            None => return,
+        };
+        let child_node = match unsafe { child_shared.as_ref() } {
+            Some(child_node) => child_node,
+            None => return,
+        };
+
+        if !child_node.value.load_consume(guard).is_null() {
+            return;
         }
-        self.set_value(value, guard).ok_or(Error::NotFound)
+
+        // The pin keeps this node from being removed under the merge,
+        // so that a removal of the subtree finds the relinked node.
+        let _pin = match self.pin() {
+            Some(pin) => pin,
+            None => return,
+        };
+
+        self.children.get(seg, guard, |link| {
+            if link.load::<Self>(guard) != child_shared {
+                return;
+            }
+
+            let child_freeze = match child_node.state.try_freeze() {
+                Some(freeze) => freeze,
+                None => return,
+            };
+
+            // A split may have relinked the child before it was frozen.
+            if link.load::<Self>(guard) != child_shared
+                || !child_node.value.load_consume(guard).is_null()
+            {
+                return;
+            }
+
+            let mut num_children = 0;
+            let mut grandchild = None;
+            child_node.children.for_each(guard, |seg, link| {
+                if let Some(node) = unsafe { link.load::<Self>(guard).as_ref() } {
+                    num_children += 1;
+                    grandchild = Some((clone_seg(seg), node));
+                }
+            });
+
+            let (grandchild_seg, grandchild) = match grandchild {
+                Some(grandchild) if num_children == 1 => grandchild,
+                _ => return,
+            };
+
+            // A grandchild shared with a snapshot is left where it is.
+            if grandchild.gen() != child_node.gen() {
+                return;
+            }
+
+            let _grandchild_freeze = match grandchild.state.try_freeze() {
+                Some(freeze) => freeze,
+                None => return,
+            };
+
+            if grandchild.is_empty(guard) {
+                return;
+            }
+
+            // The run of the grandchild may still cover the new tail
+            // from an earlier merge. It is only ever replaced by a
+            // longer one, as writers may still read it as the tail
+            // below any node it was linked under before.
+            let len = grandchild.depth - self.depth - 1;
+            let run = grandchild.run.load(Acquire, guard);
+            let old_run = match unsafe { run.as_ref() } {
+                Some(run) if run.len() >= len => Shared::null(),
+                _ => {
+                    let child_tail = child_node.tail(self.depth, guard);
+                    let mut run: Vec<S> = child_tail.iter().map(clone_seg).collect();
+                    run.push(clone_seg(&grandchild_seg));
+                    run.extend(
+                        grandchild
+                            .tail(child_node.depth, guard)
+                            .iter()
+                            .map(clone_seg),
+                    );
+                    grandchild.run.swap(Owned::new(run), AcqRel, guard)
+                }
+            };
+
+            // Nothing but this merge replaces a frozen child.
+            let grandchild_shared = Shared::from(grandchild as *const Self);
+            let result = link.compare_exchange(child_shared, grandchild_shared, guard);
+            assert!(result.is_ok());
+            self.touch();
+            grandchild.moves.fetch_add(1, AcqRel);
+
+            // Readers may still reach the grandchild through the merged
+            // child, which must not free it.
+            child_node.children.get(&grandchild_seg, guard, |link| {
+                link.set_moved(grandchild_shared, guard)
+            });
+
+            child_freeze.retire();
+            unsafe {
+                if !old_run.is_null() {
+                    guard.defer_destroy(old_run);
+                }
+                Self::release(child_shared, guard);
+            }
+        });
+    }
+
+    /// Pins the node against being marked removed. Returns `None` if
+    /// it is removed already.
+    pub fn pin(&self) -> Option<PinGuard<'_>> {
+        self.state.pin()
     }
 
-    pub fn remove_at<'a, 'g, Q, K>(
+    /// Sets the value if the node has none. Otherwise, the current
+    /// value is returned along with `value`. The caller must hold the
+    /// pin on this node, and `path` holds the nodes from the root
+    /// down to this node.
+    pub fn insert_if_absent<'g>(
         &self,
+        value: V,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Result<&'g V, (&'g V, V)> {
+        let guard = &trie.guard;
+
+        // Counts and the length of the trie are raised ahead of the
+        // value showing up, so that a concurrent removal never takes
+        // them below zero.
+        add_count(path, 1);
+        trie.trie.len.fetch_add(1, Relaxed);
+        let result =
+            self.value
+                .compare_exchange(Shared::null(), Value::new(value), AcqRel, Acquire, guard);
+
+        match result {
+            Ok(new) => {
+                self.touch();
+                Ok(unsafe { &new.deref().value })
+            }
+            Err(error) => {
+                trie.trie.len.fetch_sub(1, Relaxed);
+                sub_count(path, 1);
+                let current = unsafe { &error.current.deref().value };
+                Err((current, error.new.into_box().value))
+            }
+        }
+    }
+
+    /// Replaces the value if it is still `current`. Otherwise, the
+    /// current value is returned along with `new_value`.
+    pub fn compare_exchange_value<'g>(
+        &self,
+        current: &'g V,
+        new_value: V,
+        guard: &'g Guard,
+    ) -> Result<&'g V, (Option<&'g V>, V)> {
+        let current = Value::as_shared(current);
+        let result =
+            self.value
+                .compare_exchange(current, Value::new(new_value), AcqRel, Acquire, guard);
+
+        match result {
+            Ok(new) => unsafe {
+                self.touch();
+                Value::release(current, guard);
+                Ok(&new.deref().value)
+            },
+            Err(error) => Err((
+                unsafe { Value::as_ref(error.current) },
+                error.new.into_box().value,
+            )),
+        }
+    }
+
+    /// Walks down `key` and runs `remove` on the node found there,
+    /// along with the pin for removal and the path of nodes from the
+    /// root down to it. `remove` tells whether the node turned empty
+    /// and is marked removed, in which case it is unlinked from its
+    /// parent, and the ancestors that become empty in turn are unlinked
+    /// as well.
+    ///
+    /// `moves` is the number of times this node was relinked when it
+    /// was reached. If `prefix` is set, the key may end within the tail
+    /// of a node. In a compressed trie, the path is pinned until
+    /// `remove` returns, like by a writer, and the node left with a
+    /// single child on the way back up is merged away.
+    pub fn remove_at<'a, 'g, Q, K, R, F>(
+        &'g self,
         key: K,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> Result<(&'g V, bool), Error>
+        moves: usize,
+        prefix: bool,
+        path: &mut Vec<&'g Node<S, V, H, C>>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+        remove: F,
+    ) -> Result<(R, bool), Error>
     where
         K: IntoIterator<Item = &'a Q>,
-        S: Borrow<Q>,
-        Q: Hash + Eq + 'a,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+        F: FnOnce(&'g Self, Option<PinGuard<'g>>, &[&'g Self]) -> Result<(R, bool), Error>,
     {
         let mut key = key.into_iter();
         let guard = &trie.guard;
+        let mut node = self;
+        let mut moves = moves;
 
-        // Get the value
-        let (value, is_self_deleted) = match key.next() {
-            Some(seg) => {
-                // Find the related child
-                let child_shared = {
-                    let is_deleted = self.is_deleted.read().unwrap();
-                    if *is_deleted {
-                        return Err(Error::Retry);
-                    }
+        // The links followed on the way down, each along with the node
+        // it belongs to.
+        let mut links = vec![];
+        let mut pins = vec![];
 
-                    let entry = self
-                        .children(guard)
-                        .ok_or(Error::NotFound)?
-                        .get(seg)
-                        .ok_or(Error::NotFound)?;
-                    let atomic = entry.value();
-                    atomic.load_consume(guard)
-                };
-                let child_node = unsafe { child_shared.as_ref().ok_or(Error::NotFound)? };
+        let (value, mut is_deleted) = loop {
+            path.push(node);
+            let retry = Error::Retry {
+                operation: Operation::Remove,
+                depth: node.depth,
+            };
+            let not_found = Error::NotFound {
+                operation: Operation::Remove,
+                depth: node.depth,
+            };
 
-                // Delete the value in descendents. During the
-                // process, the hash map entry for the child may be
-                // set to null.
-                let (value, is_child_deleted) = child_node.remove_at(key, trie)?;
+            let seg = key.next();
+            let pin = match (node.pin_for_removal(trie), &seg) {
+                (Ok(pin), _) => pin,
+                // A removed node on the way may be replaced by a writer.
+                (Err(_), Some(_)) => return Err(retry),
+                (Err(error), None) => return Err(error),
+            };
+            if pin.is_some() && node.moves() != moves {
+                return Err(retry);
+            }
 
-                let is_self_deleted = {
-                    let mut is_deleted = self.is_deleted.write().unwrap();
+            let seg = match seg {
+                Some(seg) => seg,
+                None => break remove(node, pin, path)?,
+            };
+            pins.extend(pin);
 
-                    // Check if some deleter else removes this node already.
-                    if *is_deleted {
-                        return Ok((value, false));
-                    }
+            // Find the related child
+            if node.is_removed() {
+                return Err(retry);
+            }
 
-                    let is_self_deleted = match self.children(guard) {
-                        Some(children) => {
-                            // If the child was deleted, try to remove the
-                            // corresponding entry if the entry was not
-                            // altered.
-                            if is_child_deleted {
-                                children.remove_if(seg, |_, atomic| {
-                                    let result = atomic.compare_exchange(
-                                        child_shared,
-                                        Shared::null(),
-                                        AcqRel,
-                                        Release,
-                                        guard,
-                                    );
-                                    result.is_ok()
-                                });
-                            }
-
-                            children.is_empty() && self.value.load_consume(guard).is_null()
-                        }
-                        None => self.value.load_consume(guard).is_null(),
-                    };
+            let (child_node, child_moves) =
+                load_with_moves(|| node.child_for_write(seg, trie)).ok_or(not_found)?;
 
-                    if is_self_deleted {
-                        *is_deleted = true;
-                    }
+            for run_seg in child_node.tail(node.depth, guard) {
+                match key.next() {
+                    Some(seg) if <C as ChildLookup<S, Q>>::matches(run_seg, seg) => {}
+                    None if prefix => break,
+                    _ => return Err(not_found),
+                }
+            }
 
-                    is_self_deleted
-                };
+            links.push((node, seg, Shared::from(child_node as *const Self)));
+            (node, moves) = (child_node, child_moves);
+        };
+
+        // The pins would keep the nodes on the path from being removed
+        // or merged.
+        drop(pins);
 
-                (value, is_self_deleted)
+        // Unlink the deleted nodes bottom-up, as long as their parents
+        // turn empty in turn. During the process, the link to a child
+        // may be set to null.
+        for (node, seg, child_shared) in links.into_iter().rev() {
+            // The first node left, whether the one found or the parent
+            // of the last one unlinked, may now have a single child.
+            // The nodes above it are as they were.
+            if !is_deleted {
+                node.try_merge(seg, child_shared, trie);
+                break;
             }
-            None => self.remove(trie)?,
-        };
 
-        Ok((value, is_self_deleted))
+            is_deleted = node.prune(Some(child_shared), guard, |children, try_unset| {
+                children.remove_if(seg, guard, try_unset)
+            });
+        }
+
+        Ok((value, is_deleted))
+    }
+
+    /// Pins the node for a remover in a compressed trie, where the node
+    /// must not be relinked, nor have a node split in above it, while
+    /// its value is taken. Removers in other tries go unpinned.
+    fn pin_for_removal(
+        &self,
+        trie: &GuardedTrie<'_, S, V, H, C>,
+    ) -> Result<Option<PinGuard<'_>>, Error> {
+        if trie.trie.clone_seg.is_none() {
+            return Ok(None);
+        }
+
+        match self.pin() {
+            Some(pin) => Ok(Some(pin)),
+            None if self.state.is_frozen() => Err(Error::Retry {
+                operation: Operation::Remove,
+                depth: self.depth,
+            }),
+            None => Err(Error::NotFound {
+                operation: Operation::Remove,
+                depth: self.depth,
+            }),
+        }
+    }
+
+    /// Unlinks `child`, which was marked removed, by scanning the child
+    /// map for it. Marks this node removed if it became empty, and
+    /// returns whether it did.
+    pub fn unlink_child<'g>(&self, child: &'g Node<S, V, H, C>, guard: &'g Guard) -> bool {
+        let child_shared = Shared::from(child as *const Node<S, V, H, C>);
+
+        self.prune(Some(child_shared), guard, |children, try_unset| {
+            children.remove_where(guard, try_unset)
+        })
+    }
+
+    /// Marks this node removed if it is empty after a removal below it.
+    ///
+    /// If a child was marked removed in the process, `unlink` is given
+    /// the child store and a predicate that marks a link to the child
+    /// dead, and returns whether it removed the link. The link is left
+    /// alone if it was altered, e.g. by an insertion replacing the
+    /// removed child.
+    fn prune<'g, F>(
+        &self,
+        removed_child: Option<Shared<'g, Node<S, V, H, C>>>,
+        guard: &'g Guard,
+        unlink: F,
+    ) -> bool
+    where
+        F: FnOnce(&C, &dyn Fn(&Link) -> bool) -> bool,
+    {
+        // Check if some deleter else removes this node already.
+        if self.is_removed() {
+            return false;
+        }
+
+        if let Some(child_shared) = removed_child {
+            let try_unset = |link: &Link| {
+                let result = link.compare_exchange(child_shared, Link::dead(), guard);
+                result.is_ok()
+            };
+
+            // The unlinked child may still be visited by
+            // concurrent readers.
+            if unlink(&self.children, &try_unset) {
+                self.touch();
+                unsafe {
+                    Self::release(child_shared, guard);
+                }
+            }
+        }
+
+        self.state.try_remove(|| self.is_empty(guard))
+    }
+
+    /// Tells whether the node holds neither a value nor children.
+    pub fn is_empty(&self, guard: &Guard) -> bool {
+        self.children.is_empty(guard) && self.value.load_consume(guard).is_null()
     }
 
-    pub fn remove<'g>(&self, trie: &'g GuardedTrie<'g, S, V, H>) -> Result<(&'g V, bool), Error> {
+    /// Removes the value of this node. If `expected` is given, the
+    /// value is only removed if it is still the expected one. `pin` is
+    /// the pin for removal, if any, which is dropped before the node is
+    /// marked removed.
+    pub fn remove<'g>(
+        &self,
+        pin: Option<PinGuard<'_>>,
+        expected: Option<&'g V>,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Result<(&'g V, bool), Error> {
         let guard = &trie.guard;
-        let mut is_deleted = self.is_deleted.write().unwrap();
+        let not_found = Error::NotFound {
+            operation: Operation::Remove,
+            depth: self.depth,
+        };
 
         // Check if some deleter else removes this node already.
-        if *is_deleted {
-            return Err(Error::NotFound);
+        if self.is_removed() {
+            return Err(not_found);
         }
 
         // Get and unset the value.
-        let value = self.take_value(guard).ok_or(Error::NotFound)?;
+        let value = match expected {
+            Some(expected) => {
+                self.take_value_if(expected, path, trie)
+                    .map_err(|_| not_found)?;
+                expected
+            }
+            None => self.take_value(path, trie).ok_or(not_found)?,
+        };
+        drop(pin);
 
         // If this node has no children, ,mark this node
         // deleted and set the entry on parent to this node to
         // null.
-        let is_self_deleted = match self.children(guard) {
-            Some(children) => children.is_empty(),
-            None => true,
-        };
+        let is_self_deleted = self.state.try_remove(|| self.is_empty(guard));
+
+        Ok((value, is_self_deleted))
+    }
+
+    /// Marks this node and all of its descendants removed, and returns
+    /// the number of values they held. `pin` is the pin for removal on
+    /// this node, if any, and `path` holds the nodes from the root down
+    /// to this node.
+    pub fn remove_subtree<'g>(
+        &'g self,
+        pin: Option<PinGuard<'g>>,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> usize {
+        let guard = &trie.guard;
+        let mut num_values = 0;
+        let mut nodes = vec![(self, pin)];
+
+        // Nodes are marked top-down, so no writer can pin a marked
+        // node from above afterwards. A writer that is already past a
+        // node pins a descendant and gets waited for when that
+        // descendant is marked.
+        while let Some((node, pin)) = nodes.pop() {
+            // A subtree shared with a snapshot is left as it is, and
+            // only dropped from this trie along with its parent.
+            if node.gen() != trie.gen() {
+                trie.trie.len.fetch_sub(node.count(), Relaxed);
+                num_values += node.count();
+                continue;
+            }
 
-        if is_self_deleted {
-            *is_deleted = true;
+            let is_removed = match pin {
+                Some(pin) => pin.remove(),
+                None => node.state.remove(),
+            };
+            if !is_removed {
+                continue;
+            }
+
+            // The removed nodes are never read again, so only the
+            // counts on the path are kept up to date.
+            if node.take_value(&[], trie).is_some() {
+                num_values += 1;
+            }
+
+            nodes.extend(node.child_nodes(guard).into_iter().map(|node| (node, None)));
         }
 
-        Ok((value, is_self_deleted))
+        sub_count(path, num_values);
+        num_values
+    }
+
+    pub fn iter_with_keys<'g>(
+        &'g self,
+        prefix: Vec<S>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        S: Clone,
+    {
+        Box::new(Entries {
+            stack: vec![(self, None, prefix.len())],
+            key: prefix,
+            trie,
+        })
     }
 
-    pub fn iter<'g>(
+    /// Iterates over the values in the subtree along with their keys,
+    /// starting at the first key not less than `prefix` followed by
+    /// `from`. The keys come in lexicographic order.
+    pub fn iter_from<'g>(
         &'g self,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> Box<dyn Iterator<Item = &'g V> + 'g> {
+        prefix: Vec<S>,
+        from: Vec<S>,
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        S: Ord + Clone,
+        C: OrderedStore<S, H>,
+    {
         let guard = &trie.guard;
-        let curr_value = iter::once_with(|| self.value(guard)).flatten();
-
-        let child_values = self
-            .children(guard)
-            .into_iter()
-            .flatten()
-            .flat_map(|entry| {
-                let child = entry.value();
-                let shared = child.load_consume(guard);
-                let ref_ = unsafe { shared.deref() };
-                ref_.iter(trie)
+        let mut stack = vec![];
+        let mut node = self;
+        let mut key = prefix;
+
+        // The segments are popped off the back.
+        let mut from = from;
+        from.reverse();
+
+        // Walk down `from`, and set aside the subtrees whose keys sort
+        // after it. Those met further down sort first, so they end up
+        // on top of the stack. The values on the way sort before
+        // `from`, and so do the children before its next segment.
+        while let Some(first) = from.pop() {
+            let start = stack.len();
+            let key_len = key.len();
+            let mut next = None;
+
+            node.children.for_each_from(&first, guard, |seg, link| {
+                let child = match unsafe { link.load::<Self>(guard).as_ref() } {
+                    Some(child) => child,
+                    None => return,
+                };
+                let tail = child.tail(node.depth, guard);
+
+                if *seg != first {
+                    stack.push((child, Some((seg.clone(), tail)), key_len));
+                    return;
+                }
+
+                // The tail of the child tells whether its keys sort
+                // before or after `from`, unless `from` goes on past it.
+                let rest = from.iter().rev().take(tail.len());
+                match tail.iter().cmp(rest) {
+                    Ordering::Less => {}
+                    Ordering::Equal => next = Some((child, tail)),
+                    Ordering::Greater => stack.push((child, Some((seg.clone(), tail)), key_len)),
+                }
             });
+            stack[start..].reverse();
+
+            match next {
+                Some((child, tail)) => {
+                    from.truncate(from.len() - tail.len());
+                    key.push(first);
+                    key.extend_from_slice(tail);
+                    node = child;
+                }
+                None => return Box::new(Entries { key, stack, trie }),
+            }
+        }
 
-        let chain = curr_value.into_iter().chain(child_values);
-        Box::new(chain)
+        stack.push((node, None, key.len()));
+        Box::new(Entries { key, stack, trie })
     }
 
     pub fn is_removed(&self) -> bool {
-        *self.is_deleted.read().unwrap()
+        self.state.is_removed()
     }
 
-    fn value<'g>(&self, guard: &'g Guard) -> Option<&'g V> {
-        let shared = self.value.load_consume(guard);
-        unsafe { shared.as_ref() }
+    pub fn value<'g>(&self, guard: &'g Guard) -> Option<&'g V> {
+        unsafe { Value::as_ref(self.value.load_consume(guard)) }
     }
 
-    fn take_value<'g>(&self, guard: &'g Guard) -> Option<&'g V> {
-        let shared = self.value.swap(Shared::null(), AcqRel, guard);
-        unsafe { shared.as_ref() }
+    /// Returns the number of values in the subtree rooted at this node.
+    pub fn count(&self) -> usize {
+        self.count.load(Acquire)
     }
 
-    fn set_value<'g>(&self, new_value: V, guard: &'g Guard) -> Option<&'g V> {
-        let new_value = Owned::new(new_value);
-        let orig_shared = self.value.swap(new_value, AcqRel, guard);
-        unsafe { orig_shared.as_ref() }
+    fn take_value<'g>(
+        &self,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g V> {
+        let guard = &trie.guard;
+        let shared = self.value.swap(Shared::null(), AcqRel, guard);
+        if !shared.is_null() {
+            self.touch();
+            trie.trie.len.fetch_sub(1, Relaxed);
+            sub_count(path, 1);
+        }
+        unsafe { Value::release(shared, guard) }
     }
 
-    fn children<'g>(&self, guard: &'g Guard) -> Option<&'g ChildMap<S, V, H>> {
-        let shared = self.children.load_consume(guard);
-        unsafe { shared.as_ref() }
+    /// Unsets the value if it is still `current`. Otherwise, the
+    /// current value is returned.
+    pub fn take_value_if<'g>(
+        &self,
+        current: &'g V,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Result<(), Option<&'g V>> {
+        let guard = &trie.guard;
+        let current = Value::as_shared(current);
+        let result = self
+            .value
+            .compare_exchange(current, Shared::null(), AcqRel, Acquire, guard);
+
+        match result {
+            Ok(_) => unsafe {
+                self.touch();
+                trie.trie.len.fetch_sub(1, Relaxed);
+                sub_count(path, 1);
+                Value::release(current, guard);
+                Ok(())
+            },
+            Err(error) => Err(unsafe { Value::as_ref(error.current) }),
+        }
     }
 
-    fn get_or_create_children<'g>(
+    /// Sets the value and returns the replaced one. The caller must
+    /// hold the pin on this node, and `path` holds the nodes from the
+    /// root down to this node.
+    pub fn set_value<'g>(
         &self,
-        trie: &'g GuardedTrie<'g, S, V, H>,
-    ) -> &'g ChildMap<S, V, H> {
+        new_value: V,
+        path: &[&Node<S, V, H, C>],
+        trie: &'g GuardedTrie<'g, S, V, H, C>,
+    ) -> Option<&'g V> {
         let guard = &trie.guard;
+        let new_value = Value::new(new_value);
 
-        match self.children(guard) {
-            Some(children) => children,
-            None => {
-                let map = Owned::new(new_map(&trie.trie.hash_builder));
-                let result =
-                    self.children
-                        .compare_exchange(Shared::null(), map, AcqRel, Acquire, guard);
-                let shared = match result {
-                    Ok(curr) => curr,
-                    Err(error) => error.current,
+        // See insert_if_absent() for why counts are raised first.
+        add_count(path, 1);
+        trie.trie.len.fetch_add(1, Relaxed);
+        let orig_shared = self.value.swap(new_value, AcqRel, guard);
+        self.touch();
+        if !orig_shared.is_null() {
+            trie.trie.len.fetch_sub(1, Relaxed);
+            sub_count(path, 1);
+        }
+        unsafe { Value::release(orig_shared, guard) }
+    }
+}
+
+impl<S, V, H, C> Drop for Node<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    fn drop(&mut self) {
+        // The node is unreachable once it is dropped, and so are the
+        // descendants, which are dropped along with it. They are moved
+        // out to a stack first, so that each is dropped without any
+        // children left, however deep the trie is.
+        unsafe {
+            let mut nodes = vec![];
+            self.take_children(&mut nodes);
+            while let Some(mut node) = nodes.pop() {
+                node.take_children(&mut nodes);
+            }
+
+            let value = self.value.load(Relaxed, epoch::unprotected());
+            if !value.is_null() {
+                Value::release_now(value.as_raw());
+            }
+            drop(mem::take(&mut self.run).try_into_owned());
+        }
+    }
+}
+
+impl<S, V, H, C> Node<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// Moves the children this node owns to `nodes`.
+    ///
+    /// # Safety
+    ///
+    /// The node must be unreachable by any other thread.
+    unsafe fn take_children(&mut self, nodes: &mut Vec<Owned<Self>>) {
+        let guard = epoch::unprotected();
+        self.children.for_each(guard, |_, link| {
+            let child = link.load::<Self>(guard);
+            if !child.as_raw().is_null() && !link.is_moved() {
+                link.set_moved(child, guard);
+                // A child shared with a snapshot outlives this node.
+                if child.deref().refs.fetch_sub(1, AcqRel) == 1 {
+                    nodes.push(child.into_owned());
+                }
+            }
+        });
+    }
+}
+
+/// Iterates over the values in a subtree along with their keys,
+/// depth-first.
+struct Entries<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// The key of the node visited last. The nodes left to visit are
+    /// below the nodes on its path, so each key is built by cutting it
+    /// back to the key of the parent and extending it, and is only
+    /// cloned when yielded.
+    key: Vec<S>,
+    /// The nodes left to visit, the next one on top.
+    stack: Vec<Pending<'g, S, V, H, C>>,
+    trie: &'g GuardedTrie<'g, S, V, H, C>,
+}
+
+impl<'g, S, V, H, C> Iterator for Entries<'g, S, V, H, C>
+where
+    S: Clone,
+    C: ChildStore<S, H>,
+{
+    type Item = (Vec<S>, &'g V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let guard = &self.trie.guard;
+
+        while let Some((node, step, parent_key_len)) = self.stack.pop() {
+            self.key.truncate(parent_key_len);
+            if let Some((seg, tail)) = step {
+                self.key.push(seg);
+                self.key.extend_from_slice(tail);
+            }
+
+            let start = self.stack.len();
+            let key_len = self.key.len();
+            node.children.for_each(guard, |seg, link| {
+                let child = match unsafe { link.load::<Node<S, V, H, C>>(guard).as_ref() } {
+                    Some(child) => child,
+                    None => return,
                 };
-                unsafe { shared.deref() }
+                let step = (seg.clone(), child.tail(node.depth, guard));
+                self.stack.push((child, Some(step), key_len));
+            });
+            self.stack[start..].reverse();
+
+            if let Some(value) = node.value(guard) {
+                return Some((self.key.clone(), value));
             }
         }
+        None
     }
 }
 
-impl<S, V, H> Default for Node<S, V, H>
+fn add_count<S, V, H, C>(path: &[&Node<S, V, H, C>], n: usize)
 where
-    S: Eq + Hash,
-    H: BuildHasher + Clone,
+    C: ChildStore<S, H>,
 {
-    fn default() -> Self {
-        Self::new()
+    for node in path {
+        node.count.fetch_add(n, AcqRel);
     }
 }
 
-fn load_atomic<'g, T>(atomic: &Atomic<T>, guard: &'g Guard) -> Option<&'g T> {
-    unsafe { atomic.load_consume(guard).as_ref() }
+fn sub_count<S, V, H, C>(path: &[&Node<S, V, H, C>], n: usize)
+where
+    C: ChildStore<S, H>,
+{
+    for node in path {
+        node.count.fetch_sub(n, AcqRel);
+    }
 }
 
-fn new_map<K, V, H>(build_hasher: &H) -> DashMap<K, V, H>
+/// Loads a child through `load` along with the number of times it was
+/// relinked, read while `load` kept returning the same child. Returns
+/// `None` if there is no child.
+fn load_with_moves<'g, S, V, H, C, F>(load: F) -> Option<WithMoves<'g, S, V, H, C>>
 where
-    K: Hash + Eq,
-    H: BuildHasher + Clone,
+    C: ChildStore<S, H>,
+    F: Fn() -> Shared<'g, Node<S, V, H, C>>,
 {
-    static DEFAULT_SHARD_AMOUNT: Lazy<usize> =
-        Lazy::new(|| (available_parallelism().map_or(1, usize::from) * 4).next_power_of_two());
-
-    DashMap::with_capacity_and_hasher_and_shard_amount(
-        0,
-        build_hasher.clone(),
-        *DEFAULT_SHARD_AMOUNT,
-    )
+    loop {
+        let shared = load();
+        let node = unsafe { shared.as_ref()? };
+        let moves = node.moves();
+        if load() == shared {
+            break Some((node, moves));
+        }
+    }
+}
+
+impl<V> Value<V> {
+    fn new(value: V) -> Owned<Self> {
+        Owned::new(Self {
+            value,
+            refs: AtomicUsize::new(1),
+        })
+    }
+
+    /// Returns the pointer to the value that `value` is a reference to.
+    fn as_shared<'g>(value: &'g V) -> Shared<'g, Self> {
+        Shared::from(value as *const V as *const Self)
+    }
+
+    /// # Safety
+    ///
+    /// See [`Shared::as_ref`].
+    unsafe fn as_ref<'g>(shared: Shared<'g, Self>) -> Option<&'g V> {
+        shared.as_ref().map(|held| &held.value)
+    }
+
+    /// Drops the hold of a node on the value once no pinned thread can
+    /// observe it, and the value along with it unless another node
+    /// holds it. Returns a reference valid for the guard's lifetime.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have unset the value, and must do so only once
+    /// per hold.
+    unsafe fn release<'g>(shared: Shared<'g, Self>, guard: &'g Guard) -> Option<&'g V> {
+        let value = Self::as_ref(shared)?;
+        let raw = shared.as_raw();
+        guard.defer_unchecked(move || Self::release_now(raw));
+        Some(value)
+    }
+
+    /// Drops the hold of a node on the value right away, like
+    /// [`Self::release`].
+    ///
+    /// # Safety
+    ///
+    /// No other thread may observe the value through the dropped hold.
+    unsafe fn release_now(value: *const Self) {
+        if (*value).refs.fetch_sub(1, AcqRel) == 1 {
+            drop(Owned::from_raw(value as *mut Self));
+        }
+    }
 }
@@ -0,0 +1,226 @@
+use crate::iter::Iter;
+use crate::store::{AdaptiveStore, ChildLookup, ChildStore, OrderedStore};
+use crate::{GuardedTrie, Trie};
+use crossbeam::epoch::{self, Atomic};
+use crossbeam::sync::ShardedLock;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+use std::sync::PoisonError;
+use std::vec;
+
+/// Hands out the generations renewed by snapshots. Tries start out in
+/// generation zero, which is never shared.
+static NEXT_GEN: AtomicUsize = AtomicUsize::new(1);
+
+impl<S, V, H, C> Trie<S, V, H, C>
+where
+    S: Clone,
+    H: Clone,
+    C: ChildStore<S, H>,
+{
+    /// Takes a snapshot of the trie, which holds the values in the trie
+    /// at the time of the call and is not affected by later writes.
+    ///
+    /// Like in a Ctrie, taking the snapshot is O(1), as the snapshot
+    /// shares the nodes with the trie. Both get a new generation, and a
+    /// writer to either copies a node of an older generation before it
+    /// writes to it, along with the nodes on the path to it. A copy
+    /// holds the same value as the original, so the values need not be
+    /// `Clone`. Both tries can be written to on their own afterwards.
+    ///
+    /// Unlike in a Ctrie, whose nodes have at most 32 children, a copy
+    /// links to every child of the original. The first write below a
+    /// node after a snapshot thus takes time in the number of children
+    /// of each node it copies, O(fanout × depth) for a key, e.g. it
+    /// copies a million links below a root with a million children.
+    /// Later writes only copy the nodes that no writer copied yet.
+    /// `examples/snapshot_benchmark.rs` measures both.
+    ///
+    /// Unlike in a Ctrie, taking the snapshot waits for the writes in
+    /// flight to finish, and holds off new ones for its duration. No
+    /// user code runs within a write, so the closure passed to
+    /// [`GuardedTrie::update`] may take a snapshot as well.
+    pub fn snapshot(&self) -> Self {
+        let _gate = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        self.fork()
+    }
+
+    /// Takes a snapshot while the caller holds the gate exclusively.
+    pub(crate) fn fork(&self) -> Self {
+        let snapshot = self.share_root();
+        self.gen.store(NEXT_GEN.fetch_add(1, Relaxed), Release);
+        snapshot
+    }
+
+    /// Creates a trie sharing the root of this one, in a generation of
+    /// its own, so that its writers copy the nodes of this trie rather
+    /// than write to them.
+    pub(crate) fn share_root(&self) -> Self {
+        self.copy_seg.get_or_init(|| S::clone);
+
+        // The root is released by its remover only once this thread
+        // unpins, so it can still be shared if it was just unset.
+        let guard = &epoch::pin();
+        let root = self.root.load(Acquire, guard);
+        if let Some(root) = unsafe { root.as_ref() } {
+            root.share();
+        }
+
+        Self {
+            root: Atomic::from(root),
+            hash_builder: self.hash_builder.clone(),
+            len: AtomicUsize::new(self.len()),
+            clone_seg: self.clone_seg,
+            gen: AtomicUsize::new(NEXT_GEN.fetch_add(1, Relaxed)),
+            gate: ShardedLock::new(()),
+            copy_seg: self.copy_seg.clone(),
+        }
+    }
+
+    /// Takes a snapshot like [`Trie::snapshot`], which can only be read.
+    ///
+    /// The trie still copies the nodes it shares with the snapshot when
+    /// it writes to them.
+    pub fn read_only_snapshot(&self) -> ReadOnlyTrie<S, V, H, C> {
+        ReadOnlyTrie(self.snapshot())
+    }
+}
+
+/// A snapshot of a trie that can only be read. Created by
+/// [`Trie::read_only_snapshot`].
+#[derive(Debug)]
+pub struct ReadOnlyTrie<S, V, H = RandomState, C = AdaptiveStore<S, H>>(Trie<S, V, H, C>)
+where
+    C: ChildStore<S, H>;
+
+/// A pinned [`ReadOnlyTrie`], with the read methods of [`GuardedTrie`].
+#[derive(Debug)]
+pub struct GuardedReadOnlyTrie<'g, S, V, H, C>(GuardedTrie<'g, S, V, H, C>)
+where
+    C: ChildStore<S, H>;
+
+impl<S, V, H, C> ReadOnlyTrie<S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    pub fn is_compressed(&self) -> bool {
+        self.0.is_compressed()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn pin(&self) -> GuardedReadOnlyTrie<'_, S, V, H, C> {
+        GuardedReadOnlyTrie(self.0.pin())
+    }
+}
+
+impl<'g, S, V, H, C> GuardedReadOnlyTrie<'g, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    /// See [`GuardedTrie::get`].
+    pub fn get<'a, Q, K>(&self, key: K) -> Option<&V>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.0.get(key)
+    }
+
+    /// See [`GuardedTrie::longest_prefix`].
+    pub fn longest_prefix<'a, Q, K>(&self, key: K) -> Option<(usize, &V)>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.0.longest_prefix(key)
+    }
+
+    /// See [`GuardedTrie::ancestors`].
+    pub fn ancestors<'a, Q, K>(&self, key: K) -> vec::IntoIter<(usize, &V)>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.0.ancestors(key)
+    }
+
+    /// See [`GuardedTrie::iter`]. The iterator knows the number of
+    /// values left.
+    pub fn iter(&'g self) -> Iter<'g, S, V, H, C> {
+        self.0.iter().counted()
+    }
+
+    /// See [`GuardedTrie::iter_with_keys`].
+    pub fn iter_with_keys(&'g self) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        S: Clone,
+    {
+        self.0.iter_with_keys()
+    }
+
+    /// See [`GuardedTrie::iter_prefix`]. The iterator knows the number
+    /// of values left.
+    pub fn iter_prefix<'a, Q, K>(&'g self, prefix: K) -> Iter<'g, S, V, H, C>
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.0.iter_prefix(prefix).counted()
+    }
+
+    /// See [`GuardedTrie::count_prefix`]. The count of a snapshot is
+    /// exact.
+    pub fn count_prefix<'a, Q, K>(&self, prefix: K) -> usize
+    where
+        K: IntoIterator<Item = &'a Q>,
+        C: ChildLookup<S, Q>,
+        Q: 'a,
+    {
+        self.0.count_prefix(prefix)
+    }
+
+    /// See [`GuardedTrie::keys`].
+    pub fn keys(&'g self) -> Box<dyn Iterator<Item = Vec<S>> + 'g>
+    where
+        S: Clone,
+    {
+        self.0.keys()
+    }
+}
+
+impl<'g, S, V, H, C> GuardedReadOnlyTrie<'g, S, V, H, C>
+where
+    S: Ord + Clone,
+    C: OrderedStore<S, H>,
+{
+    /// See [`GuardedTrie::iter_from`].
+    pub fn iter_from<'a, K>(&'g self, key: K) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        K: IntoIterator<Item = &'a S>,
+        S: 'a,
+    {
+        self.0.iter_from(key)
+    }
+
+    /// See [`GuardedTrie::range`].
+    pub fn range<K, R>(&'g self, range: R) -> Box<dyn Iterator<Item = (Vec<S>, &'g V)> + 'g>
+    where
+        K: Borrow<[S]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.0.range(range)
+    }
+}
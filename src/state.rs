@@ -0,0 +1,217 @@
+use crossbeam::utils::Backoff;
+use std::hint;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+use std::thread;
+
+/// Set once the node is removed. A removed node is never revived.
+const TOMB: usize = 1 << (usize::BITS - 1);
+
+/// Set while the node is being relinked in a compressed trie. A frozen
+/// node can be neither pinned nor removed until it is thawed, though a
+/// writer that pinned it before may still mark it removed.
+const FROZEN: usize = 1 << (usize::BITS - 2);
+
+/// The number of bits counting the writers pinning the node.
+const PIN_BITS: u32 = usize::BITS / 2;
+const PIN_MASK: usize = (1 << PIN_BITS) - 1;
+
+/// Bumped every time a writer unpins the node, so a remover can tell
+/// that the node was written to since it checked the node is empty.
+const GENERATION: usize = 1 << PIN_BITS;
+const GENERATION_MASK: usize = !(TOMB | FROZEN | PIN_MASK);
+
+/// The removal state of a node, packed into a single word.
+///
+/// Readers only check whether the node is removed and never wait on
+/// it, though the child stores they go through may take locks of their
+/// own. Writers pin the node while they write to it, which keeps it
+/// from being removed under them. A remover only marks a node removed
+/// if the node is empty and no writer touched it in the meantime, but
+/// removing a whole subtree marks its nodes whatever they hold, and
+/// waits for the writers pinning them to leave.
+///
+/// Only the read path is non-blocking. Removing a subtree, removing a
+/// node through a pin, and freezing a node for a split all block until
+/// the writers pinning the node leave. Those writers never wait for a
+/// node above them in turn, so the waits end, but a stalled writer
+/// stalls them.
+#[derive(Debug)]
+pub(crate) struct State(AtomicUsize);
+
+/// Keeps a node pinned until dropped.
+#[derive(Debug)]
+pub(crate) struct PinGuard<'a>(&'a AtomicUsize);
+
+/// Keeps a node frozen until dropped.
+#[derive(Debug)]
+pub(crate) struct FreezeGuard<'a>(&'a AtomicUsize);
+
+impl State {
+    pub fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.0.load(Acquire) & TOMB != 0
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.0.load(Acquire) & FROZEN != 0
+    }
+
+    /// Pins the node against being marked removed. Returns `None` if
+    /// it is removed already, or frozen.
+    pub fn pin(&self) -> Option<PinGuard<'_>> {
+        self.0
+            .fetch_update(AcqRel, Acquire, |state| {
+                (state & (TOMB | FROZEN) == 0).then_some(state + 1)
+            })
+            .ok()
+            .map(|_| PinGuard(&self.0))
+    }
+
+    /// Freezes the node if no writer pins it and it is neither removed
+    /// nor frozen already.
+    pub fn try_freeze(&self) -> Option<FreezeGuard<'_>> {
+        let state = self.0.load(Acquire);
+        if state & (TOMB | FROZEN | PIN_MASK) != 0 {
+            return None;
+        }
+
+        self.0
+            .compare_exchange(state, state | FROZEN, AcqRel, Acquire)
+            .ok()
+            .map(|_| FreezeGuard(&self.0))
+    }
+
+    /// Freezes the node unless it is removed or frozen already, and
+    /// waits for the writers pinning it to leave. No writer can pin the
+    /// node in the meantime, so the wait ends. The caller checks
+    /// whether one of the writers marked the node removed on its way
+    /// out.
+    pub fn freeze(&self) -> Option<FreezeGuard<'_>> {
+        self.0
+            .fetch_update(AcqRel, Acquire, |state| {
+                (state & (TOMB | FROZEN) == 0).then_some(state | FROZEN)
+            })
+            .ok()?;
+
+        wait_unpinned(&self.0);
+        Some(FreezeGuard(&self.0))
+    }
+
+    /// Marks the node removed if `is_empty` holds and no writer pins
+    /// the node or unpins it before the mark is set. Returns whether
+    /// the node was marked.
+    ///
+    /// A node pinned at this point is left linked, as its writer is
+    /// about to fill it. If the writer ends up leaving it empty, the
+    /// node stays linked until a later removal passes by.
+    pub fn try_remove<F>(&self, is_empty: F) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        let state = self.0.load(Acquire);
+        if state & (TOMB | FROZEN | PIN_MASK) != 0 || !is_empty() {
+            return false;
+        }
+
+        self.0
+            .compare_exchange(state, state | TOMB, AcqRel, Acquire)
+            .is_ok()
+    }
+
+    /// Marks the node removed whatever it holds, and waits for the
+    /// writers pinning it to finish. Returns `false` if the node is
+    /// removed already.
+    pub fn remove(&self) -> bool {
+        let backoff = Backoff::new();
+        let state = loop {
+            let state = self.0.load(Acquire);
+            if state & FROZEN != 0 {
+                backoff.snooze();
+                continue;
+            }
+
+            if self
+                .0
+                .compare_exchange(state, state | TOMB, AcqRel, Acquire)
+                .is_ok()
+            {
+                break state;
+            }
+        };
+
+        if state & TOMB != 0 {
+            return false;
+        }
+
+        // No writer can pin the node anymore, and the pinned ones
+        // are about to leave.
+        wait_unpinned(&self.0);
+        true
+    }
+}
+
+impl PinGuard<'_> {
+    /// Marks the pinned node removed whatever it holds, like
+    /// [`State::remove`]. The node may be frozen under the pin, in
+    /// which case its freezer gives up once the pins are gone.
+    pub fn remove(self) -> bool {
+        let state = self.0;
+        if state.fetch_or(TOMB, AcqRel) & TOMB != 0 {
+            return false;
+        }
+
+        drop(self);
+        wait_unpinned(state);
+        true
+    }
+}
+
+impl FreezeGuard<'_> {
+    /// Leaves the node frozen for good, e.g. once it is merged away.
+    /// Unlike a removed node, readers still pass through it.
+    pub fn retire(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for PinGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .0
+            .fetch_update(AcqRel, Relaxed, |state| Some(unpinned(state)));
+    }
+}
+
+impl Drop for FreezeGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.fetch_update(AcqRel, Relaxed, |state| {
+            let generation = (state & GENERATION_MASK).wrapping_add(GENERATION) & GENERATION_MASK;
+            Some(state & !(FROZEN | GENERATION_MASK) | generation)
+        });
+    }
+}
+
+/// Blocks until no writer pins the node, spinning at first and then
+/// yielding. New pins are kept off by the caller.
+fn wait_unpinned(state: &AtomicUsize) {
+    let mut num_spins = 0u32;
+    while state.load(Acquire) & PIN_MASK != 0 {
+        if num_spins < 64 {
+            hint::spin_loop();
+            num_spins += 1;
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Drops a pin from `state` and bumps the generation, which wraps
+/// around without touching the tomb bit.
+fn unpinned(state: usize) -> usize {
+    let generation = (state & GENERATION_MASK).wrapping_add(GENERATION) & GENERATION_MASK;
+    state & (TOMB | FROZEN) | generation | ((state & PIN_MASK) - 1)
+}
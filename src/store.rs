@@ -0,0 +1,297 @@
+//! The maps holding the children of a node.
+//!
+//! Each node keeps its children in a [`ChildStore`], which the trie
+//! picks through its last type parameter. The default
+//! [`AdaptiveStore`] suits most tries. The other stores trade it for a
+//! particular fanout or ordering:
+//!
+//! - [`DashStore`] keeps the children in a sharded hash map throughout.
+//! - [`SplitOrderedStore`] is a lock-free hash map.
+//! - [`SkipListStore`] keeps the children sorted by segment.
+//! - [`ByteStore`] is a fixed array of 256 slots for `u8` segments.
+//! - [`ArtStore`] sizes the nodes of an adaptive radix tree to their
+//!   number of children, for `u8` segments.
+//!
+//! The last three are [`OrderedStore`]s, which the ordered scans of a
+//! trie, e.g. [`GuardedTrie::range`](crate::GuardedTrie::range), rely
+//! on.
+//!
+//! The trie takes no locks of its own on the read path, but a store
+//! may: the readers of a [`DashStore`], or of an [`AdaptiveStore`] with
+//! many children, take the read locks of the maps they look children
+//! up in, and so wait for the writers holding those maps.
+
+mod adaptive;
+mod art;
+mod byte_array;
+mod dash;
+mod skip_list;
+mod split_ordered;
+
+pub use adaptive::AdaptiveStore;
+pub use art::ArtStore;
+pub use byte_array::ByteStore;
+pub use dash::DashStore;
+pub use skip_list::SkipListStore;
+pub use split_ordered::SplitOrderedStore;
+
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::atomic::Ordering::*;
+use std::thread::available_parallelism;
+
+/// The tag of a link whose child is being unlinked.
+const DEAD: usize = 1;
+
+/// The tag of a link whose child was merged into the place of its
+/// parent in a compressed trie. The parent no longer owns the child.
+const MOVED: usize = 2;
+
+/// The link from a node to one of its children.
+///
+/// A link starts out vacant and the trie fills it in with a child.
+/// When the child is unlinked, the trie marks the link dead, and the
+/// store then either drops the link or revives it. The store never
+/// owns the child itself, which is freed by the trie.
+#[derive(Debug)]
+pub struct Link(Atomic<Erased>);
+
+/// The type of the linked nodes, erased so that the stores need not
+/// name it. Nodes are aligned to at least 4 bytes, which leaves room
+/// for the tag.
+#[repr(align(4))]
+struct Erased;
+
+/// A concurrent map from segments to the links to the children of a
+/// node.
+///
+/// Every node embeds a store, and every operation runs while `guard`
+/// is pinned. Lookups go through [`ChildLookup`], so that each store
+/// can ask for the bounds it needs on borrowed segments, e.g. `Hash`
+/// or `Ord`.
+///
+/// # Safety
+///
+/// `for_each` must visit each link holding a child exactly once, and
+/// links must not be dropped while they hold a child, i.e. before
+/// they are marked dead. The trie frees the children of a dropped node
+/// through `for_each`. A store that leaves segments to be dropped
+/// through the guard must ask for `S: Send + 'static`, as the drops
+/// may run on another thread.
+pub unsafe trait ChildStore<S, H>: ChildLookup<S, S> {
+    /// Creates an empty store. Most nodes are leaves, so a store should
+    /// not allocate before it gets its first link.
+    fn new() -> Self;
+
+    /// Tells whether the store holds no links, other than vacant or
+    /// dead ones.
+    fn is_empty(&self, guard: &Guard) -> bool;
+
+    /// Calls `f` on each segment and its link. Vacant and dead links
+    /// may be skipped.
+    fn for_each<F>(&self, guard: &Guard, f: F)
+    where
+        F: FnMut(&S, &Link);
+
+    /// Runs `f` on the link at `seg`, inserting a vacant link first if
+    /// there is none. `f` returns `None` if it finds the link dead, in
+    /// which case the store revives the link or replaces it with a
+    /// vacant one, and runs `f` again. Stores that hash the segments
+    /// build their hashers with `hash_builder`.
+    fn with_link<R, F>(&self, seg: S, hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>;
+
+    /// Removes the first link that `unset` marks dead, and returns
+    /// whether there was one.
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool;
+}
+
+/// A [`ChildStore`] keeping the children sorted by segment, so that
+/// `for_each` visits the links in ascending segment order.
+pub trait OrderedStore<S, H>: ChildStore<S, H> {
+    /// Calls `f` on each segment not less than `seg` and its link, in
+    /// ascending segment order. Vacant and dead links may be skipped.
+    fn for_each_from<F>(&self, seg: &S, guard: &Guard, f: F)
+    where
+        F: FnMut(&S, &Link);
+}
+
+/// Lookups in a [`ChildStore`] by a borrowed form `Q` of the segments.
+pub trait ChildLookup<S, Q> {
+    /// Runs `f` on the link at `seg`, if there is one.
+    fn get<R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R;
+
+    /// Removes the link at `seg` if `unset` marks it dead, and returns
+    /// whether it did.
+    fn remove_if(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool;
+
+    /// Tells whether `key` looks up `seg`. Compressed tries match the
+    /// runs of segments kept in their nodes this way.
+    fn matches(seg: &S, key: &Q) -> bool;
+}
+
+impl Link {
+    /// Creates a vacant link.
+    pub fn new() -> Self {
+        Self(Atomic::null())
+    }
+
+    /// Tells whether the link was marked dead.
+    pub fn is_dead(&self) -> bool {
+        self.load_raw().tag() == DEAD
+    }
+
+    /// Turns a dead link vacant again, and returns whether it was
+    /// dead. Stores that keep their links in place revive them rather
+    /// than dropping them.
+    pub fn revive(&self) -> bool {
+        let guard = unsafe { epoch::unprotected() };
+        self.0
+            .compare_exchange(
+                Shared::null().with_tag(DEAD),
+                Shared::null(),
+                AcqRel,
+                Acquire,
+                guard,
+            )
+            .is_ok()
+    }
+
+    /// Tells whether the link holds a child.
+    pub(crate) fn is_set(&self) -> bool {
+        !self.load_raw().is_null()
+    }
+
+    /// Loads the link as a pointer to `T`, which is null if the link
+    /// is vacant and null with the dead tag set if it is dead.
+    pub(crate) fn load<'g, T>(&self, guard: &'g Guard) -> Shared<'g, T> {
+        unerase(self.0.load_consume(guard))
+    }
+
+    /// Replaces `current` with `new` if the link still holds it, or
+    /// returns what the link holds otherwise.
+    pub(crate) fn compare_exchange<'g, T>(
+        &self,
+        current: Shared<'g, T>,
+        new: Shared<'g, T>,
+        guard: &'g Guard,
+    ) -> Result<(), Shared<'g, T>> {
+        self.0
+            .compare_exchange(erase(current), erase(new), AcqRel, Acquire, guard)
+            .map(|_| ())
+            .map_err(|error| unerase(error.current))
+    }
+
+    /// Tags the link as no longer owning `child`, which was relinked
+    /// elsewhere. Readers still follow the link to the child.
+    pub(crate) fn set_moved<'g, T>(&self, child: Shared<'g, T>, guard: &'g Guard) -> bool {
+        self.compare_exchange(child, child.with_tag(MOVED), guard)
+            .is_ok()
+    }
+
+    /// Tells whether the child was relinked elsewhere.
+    pub(crate) fn is_moved(&self) -> bool {
+        self.load_raw().tag() == MOVED
+    }
+
+    /// Returns the value a dead link holds.
+    pub(crate) fn dead<'g, T>() -> Shared<'g, T> {
+        Shared::null().with_tag(DEAD)
+    }
+
+    fn load_raw(&self) -> Shared<'_, Erased> {
+        // Only the pointer itself is looked at, never the pointee.
+        let guard = unsafe { epoch::unprotected() };
+        self.0.load(Acquire, guard)
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn erase<T>(shared: Shared<'_, T>) -> Shared<'_, Erased> {
+    Shared::from(shared.as_raw() as *const Erased).with_tag(shared.tag())
+}
+
+fn unerase<T>(shared: Shared<'_, Erased>) -> Shared<'_, T> {
+    Shared::from(shared.as_raw() as *const T).with_tag(shared.tag())
+}
+
+/// The allocation of a store, made along with its first link.
+struct LazyBox<T>(Atomic<T>);
+
+impl<T> LazyBox<T> {
+    fn new() -> Self {
+        Self(Atomic::null())
+    }
+
+    fn get<'g>(&self, guard: &'g Guard) -> Option<&'g T> {
+        unsafe { self.0.load_consume(guard).as_ref() }
+    }
+
+    fn get_or_init<'g, F>(&self, guard: &'g Guard, init: F) -> &'g T
+    where
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get(guard) {
+            return value;
+        }
+
+        let result =
+            self.0
+                .compare_exchange(Shared::null(), Owned::new(init()), AcqRel, Acquire, guard);
+        let shared = match result {
+            Ok(curr) => curr,
+            Err(error) => error.current,
+        };
+        unsafe { shared.deref() }
+    }
+}
+
+impl<T> Drop for LazyBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(mem::take(&mut self.0).try_into_owned());
+        }
+    }
+}
+
+/// Runs `f` on `link` until it finds the link alive, reviving the
+/// link whenever it is dead.
+fn with_revived<R, F>(link: &Link, mut f: F) -> R
+where
+    F: FnMut(&Link) -> Option<R>,
+{
+    loop {
+        match f(link) {
+            Some(output) => break output,
+            None => {
+                link.revive();
+            }
+        }
+    }
+}
+
+fn new_map<K, V, H>(build_hasher: &H) -> DashMap<K, V, H>
+where
+    K: Hash + Eq,
+    H: BuildHasher + Clone,
+{
+    static DEFAULT_SHARD_AMOUNT: Lazy<usize> =
+        Lazy::new(|| (available_parallelism().map_or(1, usize::from) * 4).next_power_of_two());
+
+    DashMap::with_capacity_and_hasher_and_shard_amount(
+        0,
+        build_hasher.clone(),
+        *DEFAULT_SHARD_AMOUNT,
+    )
+}
@@ -0,0 +1,571 @@
+use super::{new_map, with_revived, ChildLookup, ChildStore, Link};
+use crossbeam::epoch::{Atomic, Guard, Owned, Shared};
+use crossbeam::utils::Backoff;
+use dashmap::DashMap;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+use std::sync::Mutex;
+
+/// The number of children kept in a small array before it is promoted
+/// to a hash map.
+const SMALL_CAPACITY: usize = 8;
+
+/// A hash map shrinking to this many children is demoted back to a
+/// small array. The gap to `SMALL_CAPACITY` keeps a node on the edge
+/// from moving its children back and forth.
+const DEMOTE_LEN: usize = SMALL_CAPACITY / 2;
+
+/// Set once the children of a container are about to move to another
+/// container. A frozen container is never written to again.
+const FROZEN: usize = 1 << (usize::BITS - 1);
+
+/// The store used by default, which adapts to the number of children.
+///
+/// Children are kept in a small array while there are few of them.
+/// Readers go through the array without locking, and writers lock it
+/// and replace it with a copy. The array is promoted to a sharded hash
+/// map once it fills up, and the map is demoted back once it shrinks.
+/// Either way, the old container is frozen and replaced, and operations
+/// that find a frozen container go to its replacement. Each link has an
+/// allocation of its own, so that it stays put when it moves.
+pub struct AdaptiveStore<S, H = RandomState>(Atomic<ChildMap<S, H>>);
+
+struct ChildMap<S, H> {
+    /// The frozen flag, along with the number of threads iterating
+    /// over a hash map. A hash map is only demoted while no thread
+    /// iterates over it, so iterations never miss a moved child.
+    state: AtomicUsize,
+    slots: Slots<S, H>,
+}
+
+enum Slots<S, H> {
+    Small(Small<S>),
+    Large(DashMap<S, Box<Link>, H>),
+}
+
+/// Up to `SMALL_CAPACITY` children in an array that never changes once
+/// published. The copies made on every change share the segments and
+/// links of the array bitwise, and only the latest copy owns them.
+struct Small<S> {
+    /// Held by the writers adding or removing children, and by whoever
+    /// runs `with_link`.
+    lock: Mutex<()>,
+    children: Atomic<Children<S>>,
+}
+
+type Children<S> = Vec<(S, Box<Link>)>;
+
+/// Counts a thread iterating over a hash map until dropped.
+struct Iterating<'a>(&'a AtomicUsize);
+
+/// Tells that the container is frozen, so the operation has to be
+/// run on the container replacing it.
+struct Moved;
+
+unsafe impl<S, H> ChildStore<S, H> for AdaptiveStore<S, H>
+where
+    S: Eq + Hash + Send + 'static,
+    H: BuildHasher + Clone + Send + 'static,
+{
+    fn new() -> Self {
+        Self(Atomic::null())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        self.read(guard, |map| map.is_empty(guard)).unwrap_or(true)
+    }
+
+    fn for_each<F>(&self, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        self.read(guard, |map| map.for_each(guard, &mut f));
+    }
+
+    fn with_link<R, F>(&self, seg: S, hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let backoff = Backoff::new();
+        let mut seg = seg;
+        let mut f = f;
+
+        loop {
+            let map = self.get_or_create(guard);
+            match map.with_link(seg, &mut f, self, hash_builder, guard) {
+                Ok(output) => break output,
+                Err(orig_seg) => {
+                    seg = orig_seg;
+                    backoff.snooze();
+                }
+            }
+        }
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        self.read(guard, |map| map.remove::<S>(None, unset, self, guard))
+            .unwrap_or(false)
+    }
+}
+
+impl<S, Q, H> ChildLookup<S, Q> for AdaptiveStore<S, H>
+where
+    S: Eq + Hash + Borrow<Q> + Send + 'static,
+    Q: Hash + Eq,
+    H: BuildHasher + Clone + Send + 'static,
+{
+    fn get<R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        let mut f = Some(f);
+        self.read(guard, |map| map.get(seg, guard, &mut f))
+            .flatten()
+    }
+
+    fn remove_if(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        self.read(guard, |map| map.remove(Some(seg), unset, self, guard))
+            .unwrap_or(false)
+    }
+
+    fn matches(seg: &S, key: &Q) -> bool {
+        seg.borrow() == key
+    }
+}
+
+impl<S, H> AdaptiveStore<S, H>
+where
+    S: Eq + Hash + Send + 'static,
+    H: BuildHasher + Clone + Send + 'static,
+{
+    /// Runs `f` on the current container until it finds the container
+    /// not frozen. Returns `None` if there is no container yet.
+    fn read<R, F>(&self, guard: &Guard, mut f: F) -> Option<R>
+    where
+        F: FnMut(&ChildMap<S, H>) -> Result<R, Moved>,
+    {
+        let backoff = Backoff::new();
+
+        loop {
+            let map = unsafe { self.0.load_consume(guard).as_ref()? };
+            match f(map) {
+                Ok(output) => break Some(output),
+                Err(Moved) => backoff.snooze(),
+            }
+        }
+    }
+
+    fn get_or_create<'g>(&self, guard: &'g Guard) -> &'g ChildMap<S, H> {
+        let shared = self.0.load_consume(guard);
+        if let Some(map) = unsafe { shared.as_ref() } {
+            return map;
+        }
+
+        let map = Owned::new(ChildMap::new(Slots::Small(Small::new(vec![]))));
+        let result = self
+            .0
+            .compare_exchange(Shared::null(), map, AcqRel, Acquire, guard);
+        let shared = match result {
+            Ok(curr) => curr,
+            Err(error) => error.current,
+        };
+        unsafe { shared.deref() }
+    }
+
+    /// Replaces the frozen container `orig` with `new`.
+    fn replace<'g>(
+        &self,
+        orig: &ChildMap<S, H>,
+        new: Shared<'g, ChildMap<S, H>>,
+        guard: &'g Guard,
+    ) {
+        let orig = Shared::from(orig as *const ChildMap<S, H>);
+
+        // Only the thread that froze a container replaces it.
+        let result = self.0.compare_exchange(orig, new, AcqRel, Acquire, guard);
+        assert!(result.is_ok());
+
+        // The links were moved out, so the container goes empty.
+        unsafe {
+            guard.defer_destroy(orig);
+        }
+    }
+}
+
+impl<S, H> Drop for AdaptiveStore<S, H> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(mem::take(&mut self.0).try_into_owned());
+        }
+    }
+}
+
+impl Drop for Iterating<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, SeqCst);
+    }
+}
+
+impl<S, H> fmt::Debug for AdaptiveStore<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdaptiveStore").finish_non_exhaustive()
+    }
+}
+
+impl<S, H> ChildMap<S, H>
+where
+    S: Eq + Hash + Send + 'static,
+    H: BuildHasher + Clone + Send + 'static,
+{
+    fn new(slots: Slots<S, H>) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            slots,
+        }
+    }
+
+    fn check_frozen(&self) -> Result<(), Moved> {
+        if self.state.load(SeqCst) & FROZEN != 0 {
+            Err(Moved)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts the calling thread as iterating over the map until the
+    /// returned guard is dropped, even if `f` panics in between.
+    fn iterate(&self) -> Result<Iterating<'_>, Moved> {
+        self.state
+            .fetch_update(SeqCst, SeqCst, |state| {
+                (state & FROZEN == 0).then_some(state + 1)
+            })
+            .map_err(|_| Moved)?;
+        Ok(Iterating(&self.state))
+    }
+
+    fn get<Q, R, F>(&self, seg: &Q, guard: &Guard, f: &mut Option<F>) -> Result<Option<R>, Moved>
+    where
+        S: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnOnce(&Link) -> R,
+    {
+        let mut call = |link: &Link| f.take().map(|f| f(link));
+
+        match &self.slots {
+            Slots::Small(small) => {
+                let children = small.load(guard);
+                match children.iter().find(|(key, _)| key.borrow() == seg) {
+                    Some((_, link)) => Ok(call(link)),
+                    None => {
+                        self.check_frozen()?;
+                        Ok(None)
+                    }
+                }
+            }
+            Slots::Large(map) => match map.get(seg) {
+                Some(entry) => Ok(call(entry.value())),
+                None => {
+                    // The child may have moved out of a frozen map.
+                    self.check_frozen()?;
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    fn is_empty(&self, guard: &Guard) -> Result<bool, Moved> {
+        match &self.slots {
+            Slots::Small(small) => {
+                let children = small.load(guard);
+                if children.iter().all(|(_, link)| !link.is_set()) {
+                    self.check_frozen()?;
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+            Slots::Large(map) => {
+                if map.is_empty() {
+                    self.check_frozen()?;
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn for_each<F>(&self, guard: &Guard, f: &mut F) -> Result<(), Moved>
+    where
+        F: FnMut(&S, &Link),
+    {
+        match &self.slots {
+            Slots::Small(small) => {
+                // A promoted array is emptied, but its links are the
+                // same as in the hash map until then.
+                let children = small.load(guard);
+                if children.is_empty() {
+                    self.check_frozen()?;
+                }
+                for (seg, link) in children {
+                    f(seg, link);
+                }
+            }
+            Slots::Large(map) => {
+                // Keep the map from being demoted while iterating.
+                let _iterating = self.iterate()?;
+                for entry in map.iter() {
+                    f(entry.key(), entry.value());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn with_link<R, F>(
+        &self,
+        seg: S,
+        f: &mut F,
+        store: &AdaptiveStore<S, H>,
+        hash_builder: &H,
+        guard: &Guard,
+    ) -> Result<R, S>
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        match &self.slots {
+            Slots::Small(small) => {
+                let _lock = small.lock.lock().unwrap();
+                if self.check_frozen().is_err() {
+                    return Err(seg);
+                }
+
+                let children = small.load(guard);
+                if let Some((_, link)) = children.iter().find(|(key, _)| *key == seg) {
+                    return Ok(with_revived(link, f));
+                }
+
+                if children.len() < SMALL_CAPACITY {
+                    let mut new_children = copy_children(children);
+                    new_children.push((seg, Box::new(Link::new())));
+                    let children = small.replace(new_children, guard);
+                    return Ok(with_revived(&children.last().unwrap().1, f));
+                }
+
+                // Readers that miss a child in the array look for it in
+                // the hash map once they find the array frozen.
+                self.state.fetch_or(FROZEN, SeqCst);
+                let mut map = new_map(hash_builder);
+                map.extend(copy_children(children));
+                let map = Owned::new(ChildMap::new(Slots::Large(map)));
+                store.replace(self, map.into_shared(guard), guard);
+
+                // The links now belong to the hash map.
+                small.retire(guard);
+                Err(seg)
+            }
+            Slots::Large(map) => {
+                let entry = map.entry(seg);
+                if self.check_frozen().is_err() {
+                    return Err(entry.into_key());
+                }
+
+                let entry = entry.or_insert_with(|| Box::new(Link::new()));
+                Ok(with_revived(entry.value(), f))
+            }
+        }
+    }
+
+    /// Removes the first link that is at `seg`, if given, and that
+    /// `unset` marks dead. Returns whether there was one.
+    fn remove<Q>(
+        &self,
+        seg: Option<&Q>,
+        unset: &dyn Fn(&Link) -> bool,
+        store: &AdaptiveStore<S, H>,
+        guard: &Guard,
+    ) -> Result<bool, Moved>
+    where
+        S: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let map = match &self.slots {
+            Slots::Small(small) => {
+                let _lock = small.lock.lock().unwrap();
+                self.check_frozen()?;
+
+                let children = small.load(guard);
+                let index = children.iter().position(|(key, link)| {
+                    seg.map_or(true, |seg| key.borrow() == seg) && unset(link)
+                });
+                if let Some(index) = index {
+                    let mut new_children = copy_children(children);
+                    let child = new_children.swap_remove(index);
+                    small.replace(new_children, guard);
+                    defer_drop(child, guard);
+                }
+                return Ok(index.is_some());
+            }
+            Slots::Large(map) => map,
+        };
+
+        // A frozen map must be left as it is, so the flag is checked
+        // under the shard lock before unlinking anything.
+        let unset = |link: &Link| self.check_frozen().is_ok() && unset(link);
+        let child = match seg {
+            Some(seg) => map.remove_if(seg, |_, link| unset(link)),
+            None => {
+                // The key is copied out of the map only to look up its
+                // own entry, whose key is dropped no sooner than the
+                // guard is unpinned.
+                let seg = map
+                    .iter()
+                    .find(|entry| unset(entry.value()))
+                    .map(|entry| ManuallyDrop::new(unsafe { ptr::read(entry.key()) }));
+                match seg {
+                    // The link is dead either way, and is only left in
+                    // place if it was revived or the map was frozen.
+                    Some(seg) => {
+                        let child = map.remove_if::<S>(&seg, |_, link| {
+                            self.check_frozen().is_ok() && link.is_dead()
+                        });
+                        if child.is_none() {
+                            return Ok(true);
+                        }
+                        child
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        // Readers may still go through a copy of the key or the link
+        // in an array the map was promoted from.
+        match child {
+            Some(child) => defer_drop(child, guard),
+            None => {
+                self.check_frozen()?;
+                return Ok(false);
+            }
+        }
+
+        if map.len() <= DEMOTE_LEN {
+            self.demote(map, store, guard);
+        }
+        Ok(true)
+    }
+
+    /// Moves the links of the hash map `map` to a small array, unless
+    /// another thread is moving them already or iterating over them.
+    fn demote(&self, map: &DashMap<S, Box<Link>, H>, store: &AdaptiveStore<S, H>, guard: &Guard) {
+        let result = self.state.compare_exchange(0, FROZEN, SeqCst, SeqCst);
+        if result.is_err() {
+            return;
+        }
+
+        // The map is frozen, so the array gets all of its children,
+        // which readers of the map can go on using meanwhile.
+        let children = map
+            .iter()
+            .map(|entry| unsafe { (ptr::read(entry.key()), ptr::read(entry.value())) })
+            .collect();
+        let small = Owned::new(ChildMap::new(Slots::Small(Small::new(children))));
+        store.replace(self, small.into_shared(guard), guard);
+
+        // The children now belong to the array, so they are taken out
+        // of the map without being dropped.
+        loop {
+            // The key is copied out of the map only to look up its own
+            // entry. The map is frozen, so no one else can remove the
+            // entry and drop the key in between.
+            let seg = match map.iter().next() {
+                Some(entry) => ManuallyDrop::new(unsafe { ptr::read(entry.key()) }),
+                None => break,
+            };
+            mem::forget(map.remove(&*seg).unwrap());
+        }
+    }
+}
+
+impl<S> Small<S>
+where
+    S: Send + 'static,
+{
+    fn new(children: Children<S>) -> Self {
+        Self {
+            lock: Mutex::new(()),
+            children: Atomic::new(children),
+        }
+    }
+
+    /// Loads the current array, which is empty once it was promoted.
+    fn load<'g>(&self, guard: &'g Guard) -> &'g [(S, Box<Link>)] {
+        match unsafe { self.children.load_consume(guard).as_ref() } {
+            Some(children) => children,
+            None => &[],
+        }
+    }
+
+    /// Replaces the current array with `children`, which copies it
+    /// over, and returns the new array. The caller holds the lock.
+    fn replace<'g>(&self, children: Children<S>, guard: &'g Guard) -> &'g Children<S> {
+        let new = Owned::new(children).into_shared(guard);
+        let old = self.children.swap(new, AcqRel, guard);
+        unsafe {
+            free_copied(old, guard);
+            new.deref()
+        }
+    }
+
+    /// Unsets the current array once its children belong to another
+    /// container. The caller holds the lock.
+    fn retire(&self, guard: &Guard) {
+        let old = self.children.swap(Shared::null(), AcqRel, guard);
+        unsafe {
+            free_copied(old, guard);
+        }
+    }
+}
+
+impl<S> Drop for Small<S> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(mem::take(&mut self.children).try_into_owned());
+        }
+    }
+}
+
+/// Copies the children bitwise into a new array, which takes them over
+/// from `children`.
+fn copy_children<S>(children: &[(S, Box<Link>)]) -> Children<S> {
+    let mut copy = Vec::with_capacity(SMALL_CAPACITY);
+    copy.extend(children.iter().map(|child| unsafe { ptr::read(child) }));
+    copy
+}
+
+/// Frees an array once no guard can see it, without dropping the
+/// children it shares with its copy.
+///
+/// # Safety
+///
+/// `children` must be unlinked, and its children owned elsewhere.
+unsafe fn free_copied<S: Send + 'static>(children: Shared<'_, Children<S>>, guard: &Guard) {
+    if children.is_null() {
+        return;
+    }
+
+    guard.defer_unchecked(move || {
+        let mut children = children.into_owned();
+        children.set_len(0);
+    });
+}
+
+/// Drops a removed child once no guard can see it.
+fn defer_drop<S: Send + 'static>(child: (S, Box<Link>), guard: &Guard) {
+    unsafe {
+        guard.defer_unchecked(move || drop(child));
+    }
+}
@@ -0,0 +1,513 @@
+use super::{with_revived, ChildLookup, ChildStore, Link, OrderedStore};
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
+use std::array;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering::*};
+use std::sync::Mutex;
+
+/// The capacities of the containers, from Node4 up to Node256.
+const CAPACITIES: [usize; 4] = [4, 16, 48, 256];
+
+/// A store for tries over `u8` segments, sized like the nodes of an
+/// adaptive radix tree.
+///
+/// The links sit in one of four containers picked by their number: up
+/// to 4 or 16 links in arrays sorted by byte, up to 48 behind an index
+/// of all 256 bytes, or 256 slots indexed by the byte itself. A
+/// container grows into the next size once it overflows, and shrinks
+/// back once it is mostly empty.
+///
+/// Lookups never lock. Writers adding or removing a link lock the
+/// container, and either change it in place or replace it with a copy.
+/// Each link has an allocation of its own, so that it stays put when
+/// its container is replaced.
+pub struct ArtStore(Atomic<Container>);
+
+struct Container {
+    /// Held by the writers adding or removing links, and by whoever
+    /// runs `with_link`.
+    lock: Mutex<()>,
+    slots: Slots,
+}
+
+enum Slots {
+    Node4(Sparse<4>),
+    Node16(Box<Sparse<16>>),
+    Node48(Box<Indexed>),
+    Node256(Box<Direct>),
+}
+
+/// Up to `N` links sorted by byte. The arrays never change once the
+/// container is published, so every change makes a copy.
+struct Sparse<const N: usize> {
+    len: usize,
+    keys: [u8; N],
+    links: [Atomic<Link>; N],
+}
+
+/// Up to 48 links found through an index by byte. Links are added in
+/// place, and a slot is never reused within the same container, so a
+/// reader that looked up a slot never finds the link of another byte
+/// there.
+struct Indexed {
+    len: AtomicUsize,
+    /// The number of slots taken so far.
+    used: AtomicUsize,
+    /// One more than the slot of the link for each byte, or 0 if there
+    /// is none.
+    index: [AtomicU8; 256],
+    links: [Atomic<Link>; 48],
+}
+
+/// A slot for each byte, changed in place.
+struct Direct {
+    len: AtomicUsize,
+    links: [Atomic<Link>; 256],
+}
+
+/// Tells that the container was replaced, so the operation has to be
+/// run on the replacement.
+struct Moved;
+
+unsafe impl<H> ChildStore<u8, H> for ArtStore {
+    fn new() -> Self {
+        Self(Atomic::null())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        let container = unsafe { self.0.load_consume(guard).as_ref() };
+        container.map_or(true, |container| {
+            let link = container.slots.find_from(0, guard, |_, link| link.is_set());
+            link.is_none()
+        })
+    }
+
+    fn for_each<F>(&self, guard: &Guard, f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        self.for_each_from(0, guard, f);
+    }
+
+    fn with_link<R, F>(&self, seg: u8, _hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let mut f = f;
+        self.write(guard, |container| {
+            let link = container.get_or_insert(seg, self, guard)?;
+            Ok(with_revived(link, &mut f))
+        })
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        if self.0.load_consume(guard).is_null() {
+            return false;
+        }
+
+        self.write(guard, |container| {
+            let link = container.slots.find_from(0, guard, |_, link| unset(link));
+            if let Some((seg, link)) = link {
+                container.remove(seg, link, self, guard);
+            }
+            Ok(link.is_some())
+        })
+    }
+}
+
+impl<H> OrderedStore<u8, H> for ArtStore {
+    fn for_each_from<F>(&self, seg: &u8, guard: &Guard, f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        self.for_each_from(*seg, guard, f);
+    }
+}
+
+impl ChildLookup<u8, u8> for ArtStore {
+    fn get<R, F>(&self, seg: &u8, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        let container = unsafe { self.0.load_consume(guard).as_ref()? };
+        let link = unsafe { container.slots.find(*seg, guard).as_ref()? };
+        Some(f(link))
+    }
+
+    fn remove_if(&self, seg: &u8, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        if self.0.load_consume(guard).is_null() {
+            return false;
+        }
+
+        self.write(guard, |container| {
+            let link = container.slots.find(*seg, guard);
+            match unsafe { link.as_ref() } {
+                Some(link_ref) if unset(link_ref) => {
+                    container.remove(*seg, link, self, guard);
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        })
+    }
+
+    fn matches(seg: &u8, key: &u8) -> bool {
+        seg == key
+    }
+}
+
+impl ArtStore {
+    fn for_each_from<F>(&self, seg: u8, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        let container = match unsafe { self.0.load_consume(guard).as_ref() } {
+            Some(container) => container,
+            None => return,
+        };
+
+        container.slots.find_from(seg, guard, |seg, link| {
+            if link.is_set() {
+                f(&seg, link);
+            }
+            false
+        });
+    }
+
+    /// Runs `op` on the current container while holding its lock, until
+    /// it finds the container not replaced.
+    fn write<'g, R, F>(&self, guard: &'g Guard, mut op: F) -> R
+    where
+        F: FnMut(&'g Container) -> Result<R, Moved>,
+    {
+        loop {
+            let container = self.get_or_create(guard);
+            let _lock = container.lock.lock().unwrap();
+
+            // Containers are replaced under their lock, and freed only
+            // once no guard can see them, so the address tells whether
+            // the container is still current.
+            let shared = self.0.load(Acquire, guard);
+            if !ptr::eq(shared.as_raw(), container) {
+                continue;
+            }
+
+            if let Ok(output) = op(container) {
+                break output;
+            }
+        }
+    }
+
+    fn get_or_create<'g>(&self, guard: &'g Guard) -> &'g Container {
+        let shared = self.0.load_consume(guard);
+        if let Some(container) = unsafe { shared.as_ref() } {
+            return container;
+        }
+
+        let container = Owned::new(Container::new(CAPACITIES[0], &[]));
+        let result = self
+            .0
+            .compare_exchange(Shared::null(), container, AcqRel, Acquire, guard);
+        let shared = match result {
+            Ok(curr) => curr,
+            Err(error) => error.current,
+        };
+        unsafe { shared.deref() }
+    }
+
+    /// Replaces the locked container `orig` with a container of
+    /// `capacity` holding `links`.
+    fn replace<'g>(
+        &self,
+        orig: &Container,
+        capacity: usize,
+        links: &[(u8, Shared<'g, Link>)],
+        guard: &'g Guard,
+    ) {
+        let orig = Shared::from(orig as *const Container);
+        let new = Owned::new(Container::new(capacity, links));
+        self.0.store(new, Release);
+
+        // The links now belong to the replacement, so only the
+        // container itself is freed.
+        unsafe {
+            guard.defer_destroy(orig);
+        }
+    }
+}
+
+impl Drop for ArtStore {
+    fn drop(&mut self) {
+        let guard = unsafe { epoch::unprotected() };
+        let container = match unsafe { mem::take(&mut self.0).try_into_owned() } {
+            Some(container) => container,
+            None => return,
+        };
+
+        // The replaced containers were freed without their links, which
+        // all belong to the last one.
+        container.slots.find_from(0, guard, |_, link| {
+            unsafe {
+                drop(Shared::from(link as *const Link).into_owned());
+            }
+            false
+        });
+    }
+}
+
+impl fmt::Debug for ArtStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArtStore").finish_non_exhaustive()
+    }
+}
+
+impl Container {
+    fn new(capacity: usize, links: &[(u8, Shared<'_, Link>)]) -> Self {
+        let slots = match capacity {
+            4 => Slots::Node4(Sparse::new(links)),
+            16 => Slots::Node16(Box::new(Sparse::new(links))),
+            48 => Slots::Node48(Box::new(Indexed::new(links))),
+            _ => Slots::Node256(Box::new(Direct::new(links))),
+        };
+
+        Self {
+            lock: Mutex::new(()),
+            slots,
+        }
+    }
+
+    /// Returns the link at `seg`, adding a vacant one if there is none.
+    /// Fails if the link was added to a replacement container instead.
+    fn get_or_insert<'g>(
+        &self,
+        seg: u8,
+        store: &ArtStore,
+        guard: &'g Guard,
+    ) -> Result<&'g Link, Moved> {
+        if let Some(link) = unsafe { self.slots.find(seg, guard).as_ref() } {
+            return Ok(link);
+        }
+
+        let link = Owned::new(Link::new()).into_shared(guard);
+        if self.slots.push(seg, link) {
+            return Ok(unsafe { link.deref() });
+        }
+
+        let mut links = self.slots.links(guard);
+        let index = links.partition_point(|&(key, _)| key < seg);
+        links.insert(index, (seg, link));
+        let capacity = resize(self.slots.capacity(), links.len());
+        store.replace(self, capacity, &links, guard);
+        Err(Moved)
+    }
+
+    /// Removes the link at `seg`, which `unset` marked dead, and frees
+    /// it once no guard can see it.
+    fn remove<'g>(&self, seg: u8, link: Shared<'g, Link>, store: &ArtStore, guard: &'g Guard) {
+        let capacity = self.slots.capacity();
+        let new_capacity = resize(capacity, self.slots.len() - 1);
+
+        if new_capacity != capacity || !self.slots.take(seg) {
+            let mut links = self.slots.links(guard);
+            links.retain(|&(key, _)| key != seg);
+            store.replace(self, new_capacity, &links, guard);
+        }
+
+        unsafe {
+            guard.defer_destroy(link);
+        }
+    }
+}
+
+impl Slots {
+    fn capacity(&self) -> usize {
+        match self {
+            Slots::Node4(_) => 4,
+            Slots::Node16(_) => 16,
+            Slots::Node48(_) => 48,
+            Slots::Node256(_) => 256,
+        }
+    }
+
+    /// Returns the number of links, which only writers holding the
+    /// lock may rely on.
+    fn len(&self) -> usize {
+        match self {
+            Slots::Node4(sparse) => sparse.len,
+            Slots::Node16(sparse) => sparse.len,
+            Slots::Node48(indexed) => indexed.len.load(Relaxed),
+            Slots::Node256(direct) => direct.len.load(Relaxed),
+        }
+    }
+
+    fn find<'g>(&self, seg: u8, guard: &'g Guard) -> Shared<'g, Link> {
+        match self {
+            Slots::Node4(sparse) => sparse.find(seg, guard),
+            Slots::Node16(sparse) => sparse.find(seg, guard),
+            Slots::Node48(indexed) => match indexed.index[seg as usize].load(Acquire) {
+                0 => Shared::null(),
+                slot => indexed.links[slot as usize - 1].load(Acquire, guard),
+            },
+            Slots::Node256(direct) => direct.links[seg as usize].load(Acquire, guard),
+        }
+    }
+
+    /// Returns the first link from `seg` on, in byte order, that `pred`
+    /// holds for, along with its byte.
+    fn find_from<'g, P>(
+        &self,
+        seg: u8,
+        guard: &'g Guard,
+        mut pred: P,
+    ) -> Option<(u8, Shared<'g, Link>)>
+    where
+        P: FnMut(u8, &'g Link) -> bool,
+    {
+        let mut visit = |seg: u8, link: &Atomic<Link>| {
+            let link = link.load(Acquire, guard);
+            let link_ref = unsafe { link.as_ref()? };
+            pred(seg, link_ref).then_some((seg, link))
+        };
+
+        match self {
+            Slots::Node4(sparse) => sparse.find_from(seg, visit),
+            Slots::Node16(sparse) => sparse.find_from(seg, visit),
+            Slots::Node48(indexed) => {
+                (seg..=u8::MAX).find_map(|seg| match indexed.index[seg as usize].load(Acquire) {
+                    0 => None,
+                    slot => visit(seg, &indexed.links[slot as usize - 1]),
+                })
+            }
+            Slots::Node256(direct) => (seg..=u8::MAX)
+                .zip(&direct.links[seg as usize..])
+                .find_map(|(seg, link)| visit(seg, link)),
+        }
+    }
+
+    /// Returns all links in byte order, vacant ones included.
+    fn links<'g>(&self, guard: &'g Guard) -> Vec<(u8, Shared<'g, Link>)> {
+        let mut links = Vec::with_capacity(self.len() + 1);
+        self.find_from(0, guard, |seg, link| {
+            links.push((seg, Shared::from(link as *const Link)));
+            false
+        });
+        links
+    }
+
+    /// Adds `link` at `seg` in place, if the container has room for it.
+    fn push(&self, seg: u8, link: Shared<'_, Link>) -> bool {
+        match self {
+            Slots::Node4(_) | Slots::Node16(_) => false,
+            Slots::Node48(indexed) => {
+                let slot = indexed.used.load(Relaxed);
+                if slot == indexed.links.len() {
+                    return false;
+                }
+
+                indexed.links[slot].store(link, Release);
+                indexed.index[seg as usize].store(slot as u8 + 1, Release);
+                indexed.used.store(slot + 1, Relaxed);
+                indexed.len.fetch_add(1, Relaxed);
+                true
+            }
+            Slots::Node256(direct) => {
+                direct.links[seg as usize].store(link, Release);
+                direct.len.fetch_add(1, Relaxed);
+                true
+            }
+        }
+    }
+
+    /// Removes the link at `seg` in place, if the container allows it.
+    fn take(&self, seg: u8) -> bool {
+        match self {
+            Slots::Node4(_) | Slots::Node16(_) => false,
+            Slots::Node48(indexed) => {
+                let slot = indexed.index[seg as usize].swap(0, AcqRel);
+                indexed.links[slot as usize - 1].store(Shared::null(), Release);
+                indexed.len.fetch_sub(1, Relaxed);
+                true
+            }
+            Slots::Node256(direct) => {
+                direct.links[seg as usize].store(Shared::null(), Release);
+                direct.len.fetch_sub(1, Relaxed);
+                true
+            }
+        }
+    }
+}
+
+impl<const N: usize> Sparse<N> {
+    fn new(links: &[(u8, Shared<'_, Link>)]) -> Self {
+        Self {
+            len: links.len(),
+            keys: array::from_fn(|i| links.get(i).map_or(0, |&(seg, _)| seg)),
+            links: array::from_fn(|i| {
+                links
+                    .get(i)
+                    .map_or(Atomic::null(), |&(_, link)| link.into())
+            }),
+        }
+    }
+
+    fn find<'g>(&self, seg: u8, guard: &'g Guard) -> Shared<'g, Link> {
+        match self.keys[..self.len].iter().position(|&key| key == seg) {
+            Some(index) => self.links[index].load(Acquire, guard),
+            None => Shared::null(),
+        }
+    }
+
+    fn find_from<'g, F>(&self, seg: u8, visit: F) -> Option<(u8, Shared<'g, Link>)>
+    where
+        F: FnMut(u8, &Atomic<Link>) -> Option<(u8, Shared<'g, Link>)>,
+    {
+        let start = self.keys[..self.len].partition_point(|&key| key < seg);
+        let mut visit = visit;
+        (start..self.len).find_map(|index| visit(self.keys[index], &self.links[index]))
+    }
+}
+
+impl Indexed {
+    fn new(links: &[(u8, Shared<'_, Link>)]) -> Self {
+        let indexed = Self {
+            len: AtomicUsize::new(links.len()),
+            used: AtomicUsize::new(links.len()),
+            index: array::from_fn(|_| AtomicU8::new(0)),
+            links: array::from_fn(|i| {
+                links
+                    .get(i)
+                    .map_or(Atomic::null(), |&(_, link)| link.into())
+            }),
+        };
+        for (slot, &(seg, _)) in links.iter().enumerate() {
+            indexed.index[seg as usize].store(slot as u8 + 1, Relaxed);
+        }
+        indexed
+    }
+}
+
+impl Direct {
+    fn new(links: &[(u8, Shared<'_, Link>)]) -> Self {
+        let direct = Self {
+            len: AtomicUsize::new(links.len()),
+            links: array::from_fn(|_| Atomic::null()),
+        };
+        for &(seg, link) in links {
+            direct.links[seg as usize].store(link, Relaxed);
+        }
+        direct
+    }
+}
+
+/// Picks the capacity of a container of `capacity` that is to hold
+/// `len` links. A container grows once it overflows, but shrinks only
+/// once the links fill no more than three quarters of the smaller one,
+/// so that a container on the edge does not flip back and forth.
+fn resize(capacity: usize, len: usize) -> usize {
+    CAPACITIES
+        .into_iter()
+        .find(|&new| len <= new && (new >= capacity || len <= new * 3 / 4))
+        .unwrap()
+}
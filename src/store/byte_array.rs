@@ -0,0 +1,103 @@
+use super::{with_revived, ChildLookup, ChildStore, LazyBox, Link, OrderedStore};
+use crossbeam::epoch::Guard;
+use std::array;
+use std::fmt;
+
+/// A store with one slot per byte, for tries over `u8` segments.
+///
+/// A lookup is a plain index into the slots, and no operation ever
+/// takes a lock. Links stay in their slots for good and are revived
+/// rather than removed, so every node with children pays for all 256
+/// slots.
+pub struct ByteStore(LazyBox<[Link; 256]>);
+
+unsafe impl<H> ChildStore<u8, H> for ByteStore {
+    fn new() -> Self {
+        Self(LazyBox::new())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        let slots = self.0.get(guard);
+        slots.map_or(true, |slots| !slots.iter().any(Link::is_set))
+    }
+
+    fn for_each<F>(&self, guard: &Guard, f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        self.for_each_from(0, guard, f);
+    }
+
+    fn with_link<R, F>(&self, seg: u8, _hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let slots = self
+            .0
+            .get_or_init(guard, || array::from_fn(|_| Link::new()));
+        with_revived(&slots[seg as usize], f)
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let slots = self.0.get(guard).into_iter().flatten();
+        let link = slots.into_iter().find(|link| unset(link));
+        link.map(Link::revive).is_some()
+    }
+}
+
+impl<H> OrderedStore<u8, H> for ByteStore {
+    fn for_each_from<F>(&self, seg: &u8, guard: &Guard, f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        self.for_each_from(*seg, guard, f);
+    }
+}
+
+impl ChildLookup<u8, u8> for ByteStore {
+    fn get<R, F>(&self, seg: &u8, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        let slots = self.0.get(guard)?;
+        Some(f(&slots[*seg as usize]))
+    }
+
+    fn remove_if(&self, seg: &u8, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let link = match self.0.get(guard) {
+            Some(slots) => &slots[*seg as usize],
+            None => return false,
+        };
+        if !unset(link) {
+            return false;
+        }
+
+        // A concurrent insertion may have revived the link already.
+        link.revive();
+        true
+    }
+
+    fn matches(seg: &u8, key: &u8) -> bool {
+        seg == key
+    }
+}
+
+impl ByteStore {
+    fn for_each_from<F>(&self, seg: u8, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&u8, &Link),
+    {
+        let slots = self.0.get(guard).into_iter().flatten();
+        for (seg, link) in (seg..=u8::MAX).zip(slots.skip(seg as usize)) {
+            if link.is_set() {
+                f(&seg, link);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ByteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ByteStore").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,98 @@
+use super::{new_map, with_revived, ChildLookup, ChildStore, LazyBox, Link};
+use crossbeam::epoch::Guard;
+use dashmap::DashMap;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+/// A store keeping the children in a sharded hash map, whatever their
+/// number.
+///
+/// Lookups hold a shard lock only briefly, which suits nodes with a
+/// large fanout from the start. Each node with children pays for the
+/// whole sharded map, though, so nodes with few children are better
+/// served by [`AdaptiveStore`](super::AdaptiveStore).
+pub struct DashStore<S, H = RandomState>(LazyBox<DashMap<S, Link, H>>);
+
+unsafe impl<S, H> ChildStore<S, H> for DashStore<S, H>
+where
+    S: Eq + Hash,
+    H: BuildHasher + Clone,
+{
+    fn new() -> Self {
+        Self(LazyBox::new())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        self.0.get(guard).map_or(true, DashMap::is_empty)
+    }
+
+    fn for_each<F>(&self, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        if let Some(map) = self.0.get(guard) {
+            for entry in map.iter() {
+                f(entry.key(), entry.value());
+            }
+        }
+    }
+
+    fn with_link<R, F>(&self, seg: S, hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        // The link is unset and removed under the same shard lock, so
+        // `f` never finds it dead.
+        let map = self.0.get_or_init(guard, || new_map(hash_builder));
+        let entry = map.entry(seg).or_default();
+        with_revived(entry.value(), f)
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let map = match self.0.get(guard) {
+            Some(map) => map,
+            None => return false,
+        };
+
+        let mut is_removed = false;
+        map.retain(|_, link| {
+            let is_link = !is_removed && unset(link);
+            is_removed |= is_link;
+            !is_link
+        });
+        is_removed
+    }
+}
+
+impl<S, Q, H> ChildLookup<S, Q> for DashStore<S, H>
+where
+    S: Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq,
+    H: BuildHasher + Clone,
+{
+    fn get<R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        let entry = self.0.get(guard)?.get(seg)?;
+        Some(f(entry.value()))
+    }
+
+    fn remove_if(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        self.0
+            .get(guard)
+            .is_some_and(|map| map.remove_if(seg, |_, link| unset(link)).is_some())
+    }
+
+    fn matches(seg: &S, key: &Q) -> bool {
+        seg.borrow() == key
+    }
+}
+
+impl<S, H> fmt::Debug for DashStore<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DashStore").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,119 @@
+use super::{ChildLookup, ChildStore, LazyBox, Link, OrderedStore};
+use crossbeam::epoch::Guard;
+use crossbeam_skiplist::SkipMap;
+use std::borrow::Borrow;
+use std::fmt;
+
+/// A store keeping the children sorted by segment in a lock-free skip
+/// list.
+///
+/// Children are visited in segment order, so iterating over a trie
+/// with this store yields the keys in lexicographic order. Lookups
+/// take a logarithmic number of steps rather than the constant ones of
+/// a hash map.
+pub struct SkipListStore<S>(LazyBox<SkipMap<S, Link>>);
+
+unsafe impl<S, H> ChildStore<S, H> for SkipListStore<S>
+where
+    S: Ord + Clone + Send + 'static,
+{
+    fn new() -> Self {
+        Self(LazyBox::new())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        self.0.get(guard).map_or(true, SkipMap::is_empty)
+    }
+
+    fn for_each<F>(&self, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        if let Some(map) = self.0.get(guard) {
+            for entry in map.iter() {
+                f(entry.key(), entry.value());
+            }
+        }
+    }
+
+    fn with_link<R, F>(&self, seg: S, _hash_builder: &H, guard: &Guard, mut f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let map = self.0.get_or_init(guard, SkipMap::new);
+        loop {
+            // The segment is only cloned to insert a missing link.
+            let entry = match map.get(&seg) {
+                Some(entry) => entry,
+                None => map.get_or_insert(seg.clone(), Link::new()),
+            };
+
+            match f(entry.value()) {
+                Some(output) => break output,
+                // A dead link is on its way out, and a new one takes
+                // its place.
+                None => {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let entry = match self.0.get(guard) {
+            Some(map) => map.iter().find(|entry| unset(entry.value())),
+            None => return false,
+        };
+        entry.map(|entry| entry.remove()).is_some()
+    }
+}
+
+impl<S, H> OrderedStore<S, H> for SkipListStore<S>
+where
+    S: Ord + Clone + Send + 'static,
+{
+    fn for_each_from<F>(&self, seg: &S, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        if let Some(map) = self.0.get(guard) {
+            for entry in map.range(seg..) {
+                f(entry.key(), entry.value());
+            }
+        }
+    }
+}
+
+impl<S, Q> ChildLookup<S, Q> for SkipListStore<S>
+where
+    S: Ord + Borrow<Q> + Send + 'static,
+    Q: Ord,
+{
+    fn get<R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        let entry = self.0.get(guard)?.get(seg)?;
+        Some(f(entry.value()))
+    }
+
+    fn remove_if(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        match self.0.get(guard).and_then(|map| map.get(seg)) {
+            Some(entry) if unset(entry.value()) => {
+                entry.remove();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn matches(seg: &S, key: &Q) -> bool {
+        seg.borrow() == key
+    }
+}
+
+impl<S> fmt::Debug for SkipListStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipListStore").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,474 @@
+use super::{ChildLookup, ChildStore, LazyBox, Link};
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+
+/// The number of segments the buckets are allocated in. Segment 0
+/// holds bucket 0, and segment `i` the buckets `2^(i-1)..2^i`.
+const SEGMENTS: usize = 24;
+
+/// The number of buckets stops doubling here.
+const MAX_BUCKETS: usize = 1 << (SEGMENTS - 1);
+
+/// The average number of links per bucket that makes the number of
+/// buckets double.
+const LOAD_FACTOR: usize = 2;
+
+/// The tag on the `next` pointer of a list node that is deleted.
+const DELETED: usize = 1;
+
+/// A lock-free hash map keeping the children in a split-ordered list.
+///
+/// All links sit in a single linked list, sorted by their bit-reversed
+/// hashes. The buckets point into the list at sentinel nodes, so
+/// doubling the number of buckets only adds sentinels and never moves
+/// a link. Nothing is ever locked, at the cost of an allocation per
+/// child and a walk along the list on each lookup.
+pub struct SplitOrderedStore<S, H = RandomState>(LazyBox<Table<S, H>>);
+
+struct Table<S, H> {
+    hash_builder: H,
+    segments: [Atomic<Segment<S>>; SEGMENTS],
+    num_buckets: AtomicUsize,
+    len: AtomicUsize,
+}
+
+struct Segment<S>(Box<[Atomic<ListNode<S>>]>);
+
+struct ListNode<S> {
+    /// The bit-reversed hash. The lowest bit is set for links and
+    /// clear for the sentinels of the buckets, so that a sentinel
+    /// comes before the links of its bucket.
+    so_key: usize,
+    /// The segment of the link, or `None` for a sentinel.
+    seg: Option<S>,
+    link: Link,
+    next: Atomic<ListNode<S>>,
+}
+
+/// The place found by a search of the list: the pointer to the node
+/// found, which is null if there is none, and the node itself.
+type Position<'g, S> = (&'g Atomic<ListNode<S>>, Shared<'g, ListNode<S>>);
+
+unsafe impl<S, H> ChildStore<S, H> for SplitOrderedStore<S, H>
+where
+    S: Eq + Hash + Clone + Send + 'static,
+    H: BuildHasher + Clone,
+{
+    fn new() -> Self {
+        Self(LazyBox::new())
+    }
+
+    fn is_empty(&self, guard: &Guard) -> bool {
+        self.0.get(guard).map_or(true, Table::is_empty)
+    }
+
+    fn for_each<F>(&self, guard: &Guard, f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        if let Some(table) = self.0.get(guard) {
+            table.for_each(guard, f);
+        }
+    }
+
+    fn with_link<R, F>(&self, seg: S, hash_builder: &H, guard: &Guard, f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let table = self.0.get_or_init(guard, || Table::new(hash_builder));
+        table.with_link(seg, guard, f)
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let table = self.0.get(guard);
+        table.is_some_and(|table| table.remove_where(guard, unset))
+    }
+}
+
+impl<S, Q, H> ChildLookup<S, Q> for SplitOrderedStore<S, H>
+where
+    S: Eq + Hash + Borrow<Q> + Send + 'static,
+    Q: Hash + Eq,
+    H: BuildHasher,
+{
+    fn get<R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        F: FnOnce(&Link) -> R,
+    {
+        self.0.get(guard)?.get(seg, guard, f)
+    }
+
+    fn remove_if(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let table = self.0.get(guard);
+        table.is_some_and(|table| table.remove_if(seg, guard, unset))
+    }
+
+    fn matches(seg: &S, key: &Q) -> bool {
+        seg.borrow() == key
+    }
+}
+
+impl<S, H> Table<S, H>
+where
+    S: Eq + Hash + Clone + Send + 'static,
+    H: BuildHasher + Clone,
+{
+    fn new(hash_builder: &H) -> Self {
+        let segments: [Atomic<Segment<S>>; SEGMENTS] = Default::default();
+        let head = Atomic::new(ListNode::new(0, None));
+        segments[0].store(Owned::new(Segment(Box::new([head]))), Relaxed);
+
+        Self {
+            hash_builder: hash_builder.clone(),
+            segments,
+            num_buckets: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len.load(Acquire) == 0
+    }
+
+    fn for_each<F>(&self, guard: &Guard, mut f: F)
+    where
+        F: FnMut(&S, &Link),
+    {
+        let mut curr = self.sentinel(0, guard).next.load(Acquire, guard);
+        while let Some(node) = unsafe { curr.as_ref() } {
+            let next = node.next.load(Acquire, guard);
+            if let (Some(seg), 0) = (&node.seg, next.tag()) {
+                f(seg, &node.link);
+            }
+            curr = next.with_tag(0);
+        }
+    }
+
+    fn with_link<R, F>(&self, seg: S, guard: &Guard, mut f: F) -> R
+    where
+        F: FnMut(&Link) -> Option<R>,
+    {
+        let hash = self.hash(&seg);
+        let so_key = regular_key(hash);
+        let mut new = Owned::new(ListNode::new(so_key, Some(seg)));
+
+        loop {
+            let start = self.bucket(hash, guard);
+            let (node, rest) = match self.insert(start, new, guard) {
+                Ok(node) => {
+                    self.len.fetch_add(1, AcqRel);
+                    self.grow();
+                    (node, None)
+                }
+                Err((node, new)) => (node, Some(new)),
+            };
+
+            let node_ref = unsafe { node.deref() };
+            if let Some(output) = f(&node_ref.link) {
+                break output;
+            }
+
+            // The link is dead, so its node is deleted and a new one
+            // takes its place.
+            self.delete(start, node, guard);
+            new = rest.unwrap_or_else(|| Owned::new(ListNode::new(so_key, node_ref.seg.clone())));
+        }
+    }
+
+    fn remove_where(&self, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool {
+        let head = self.sentinel(0, guard);
+        let mut curr = head.next.load(Acquire, guard);
+
+        while let Some(node) = unsafe { curr.as_ref() } {
+            if node.seg.is_some() && unset(&node.link) {
+                self.delete(head, curr, guard);
+                return true;
+            }
+            curr = node.next.load(Acquire, guard).with_tag(0);
+        }
+        false
+    }
+}
+
+impl<S, H> Table<S, H>
+where
+    S: Eq + Send + 'static,
+    H: BuildHasher,
+{
+    fn get<Q, R, F>(&self, seg: &Q, guard: &Guard, f: F) -> Option<R>
+    where
+        S: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnOnce(&Link) -> R,
+    {
+        let hash = self.hash(seg);
+        let start = self.bucket(hash, guard);
+        let (_, node) = self.find(start, regular_key(hash), is_seg(seg), guard)?;
+        Some(f(&unsafe { node.deref() }.link))
+    }
+
+    fn remove_if<Q>(&self, seg: &Q, guard: &Guard, unset: &dyn Fn(&Link) -> bool) -> bool
+    where
+        S: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let hash = self.hash(seg);
+        let start = self.bucket(hash, guard);
+        let node = match self.find(start, regular_key(hash), is_seg(seg), guard) {
+            Some((_, node)) => node,
+            None => return false,
+        };
+
+        if !unset(&unsafe { node.deref() }.link) {
+            return false;
+        }
+        self.delete(start, node, guard);
+        true
+    }
+
+    fn hash<Q>(&self, seg: &Q) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        let mut hasher = self.hash_builder.build_hasher();
+        seg.hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    /// Returns the sentinel of the bucket `hash` falls into.
+    fn bucket<'g>(&self, hash: usize, guard: &'g Guard) -> &'g ListNode<S> {
+        let num_buckets = self.num_buckets.load(Acquire);
+        self.sentinel(hash & (num_buckets - 1), guard)
+    }
+
+    /// Returns the sentinel of `bucket`, and inserts it into the list
+    /// first if the bucket is not initialized yet.
+    fn sentinel<'g>(&self, bucket: usize, guard: &'g Guard) -> &'g ListNode<S> {
+        let (segment, index) = locate(bucket);
+        let slot = &self.segment(segment, guard).0[index];
+        if let Some(sentinel) = unsafe { slot.load(Acquire, guard).as_ref() } {
+            return sentinel;
+        }
+
+        // The bucket splits off the bucket without its highest bit,
+        // whose part of the list covers the new sentinel.
+        let parent = bucket & !(1 << (usize::BITS - 1 - bucket.leading_zeros()));
+        let start = self.sentinel(parent, guard);
+        let sentinel = Owned::new(ListNode::new(bucket.reverse_bits(), None));
+        let sentinel = match self.insert(start, sentinel, guard) {
+            Ok(sentinel) => sentinel,
+            Err((sentinel, _)) => sentinel,
+        };
+
+        // Racing initializations all find the same sentinel.
+        let _ = slot.compare_exchange(Shared::null(), sentinel, AcqRel, Acquire, guard);
+        unsafe { sentinel.deref() }
+    }
+
+    fn segment<'g>(&self, segment: usize, guard: &'g Guard) -> &'g Segment<S> {
+        let atomic = &self.segments[segment];
+        if let Some(segment) = unsafe { atomic.load(Acquire, guard).as_ref() } {
+            return segment;
+        }
+
+        let len = 1 << (segment - 1);
+        let new = Owned::new(Segment((0..len).map(|_| Atomic::null()).collect()));
+        let shared = match atomic.compare_exchange(Shared::null(), new, AcqRel, Acquire, guard) {
+            Ok(new) => new,
+            Err(error) => error.current,
+        };
+        unsafe { shared.deref() }
+    }
+
+    /// Doubles the number of buckets if they hold too many links.
+    fn grow(&self) {
+        let num_buckets = self.num_buckets.load(Acquire);
+        if num_buckets < MAX_BUCKETS && self.len.load(Acquire) > num_buckets * LOAD_FACTOR {
+            let _ =
+                self.num_buckets
+                    .compare_exchange(num_buckets, num_buckets * 2, AcqRel, Acquire);
+        }
+    }
+
+    /// Searches the list from `start` for a node with `so_key` that
+    /// `is_match` accepts, and unlinks the deleted nodes on the way.
+    /// Returns the position of the node found, or `None` along with
+    /// the position to insert at.
+    fn search<'g, F>(
+        &self,
+        start: &'g ListNode<S>,
+        so_key: usize,
+        is_match: F,
+        guard: &'g Guard,
+    ) -> Result<Position<'g, S>, Position<'g, S>>
+    where
+        F: Fn(&ListNode<S>) -> bool,
+    {
+        'retry: loop {
+            let mut prev = &start.next;
+            let mut curr = prev.load(Acquire, guard);
+
+            loop {
+                let curr_ref = match unsafe { curr.as_ref() } {
+                    Some(curr_ref) => curr_ref,
+                    None => return Err((prev, curr)),
+                };
+
+                let next = curr_ref.next.load(Acquire, guard);
+                if next.tag() == DELETED {
+                    let next = next.with_tag(0);
+                    match prev.compare_exchange(curr, next, AcqRel, Acquire, guard) {
+                        Ok(_) => unsafe {
+                            guard.defer_destroy(curr);
+                        },
+                        // The previous node was deleted or changed.
+                        Err(_) => continue 'retry,
+                    }
+                    curr = next;
+                    continue;
+                }
+
+                if curr_ref.so_key > so_key {
+                    return Err((prev, curr));
+                }
+                if curr_ref.so_key == so_key && is_match(curr_ref) {
+                    return Ok((prev, curr));
+                }
+
+                prev = &curr_ref.next;
+                curr = next;
+            }
+        }
+    }
+
+    fn find<'g, F>(
+        &self,
+        start: &'g ListNode<S>,
+        so_key: usize,
+        is_match: F,
+        guard: &'g Guard,
+    ) -> Option<Position<'g, S>>
+    where
+        F: Fn(&ListNode<S>) -> bool,
+    {
+        self.search(start, so_key, is_match, guard).ok()
+    }
+
+    /// Inserts `new` into the list, unless there is a node for the
+    /// same segment already, which is returned along with `new`.
+    #[allow(clippy::type_complexity)]
+    fn insert<'g>(
+        &self,
+        start: &'g ListNode<S>,
+        new: Owned<ListNode<S>>,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, ListNode<S>>, (Shared<'g, ListNode<S>>, Owned<ListNode<S>>)> {
+        let mut new = new;
+
+        loop {
+            let result = self.search(start, new.so_key, |node| node.seg == new.seg, guard);
+            let (prev, curr) = match result {
+                Ok((_, node)) => return Err((node, new)),
+                Err(position) => position,
+            };
+
+            new.next.store(curr, Relaxed);
+            match prev.compare_exchange(curr, new, AcqRel, Acquire, guard) {
+                Ok(node) => return Ok(node),
+                Err(error) => new = error.new,
+            }
+        }
+    }
+
+    /// Deletes `node`, which comes after `start` in the list, unless
+    /// it is deleted already.
+    fn delete<'g>(&self, start: &'g ListNode<S>, node: Shared<'g, ListNode<S>>, guard: &'g Guard) {
+        let node_ref = unsafe { node.deref() };
+        let mut next = node_ref.next.load(Acquire, guard);
+
+        loop {
+            if next.tag() == DELETED {
+                return;
+            }
+
+            let marked = next.with_tag(DELETED);
+            match node_ref
+                .next
+                .compare_exchange(next, marked, AcqRel, Acquire, guard)
+            {
+                Ok(_) => break,
+                Err(error) => next = error.current,
+            }
+        }
+        self.len.fetch_sub(1, AcqRel);
+
+        // Searching past the node unlinks it.
+        let _ = self.search(start, node_ref.so_key, |_| false, guard);
+    }
+}
+
+impl<S> ListNode<S> {
+    fn new(so_key: usize, seg: Option<S>) -> Self {
+        Self {
+            so_key,
+            seg,
+            link: Link::new(),
+            next: Atomic::null(),
+        }
+    }
+}
+
+impl<S, H> Drop for Table<S, H> {
+    fn drop(&mut self) {
+        // The deleted nodes still in the list are freed along with
+        // the rest, and the unlinked ones are left to the epoch.
+        unsafe {
+            let guard = epoch::unprotected();
+            let head = self.segments[0].load(Relaxed, guard).deref();
+            let mut curr = head.0[0].load(Relaxed, guard);
+            while !curr.is_null() {
+                let node = curr.into_owned();
+                curr = node.next.load(Relaxed, guard).with_tag(0);
+            }
+
+            for segment in &mut self.segments {
+                drop(mem::take(segment).try_into_owned());
+            }
+        }
+    }
+}
+
+impl<S, H> fmt::Debug for SplitOrderedStore<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SplitOrderedStore").finish_non_exhaustive()
+    }
+}
+
+/// Returns the split-order key of a link with `hash`.
+fn regular_key(hash: usize) -> usize {
+    (hash | 1 << (usize::BITS - 1)).reverse_bits()
+}
+
+/// Returns the segment holding `bucket` and its index there.
+fn locate(bucket: usize) -> (usize, usize) {
+    match bucket {
+        0 => (0, 0),
+        _ => {
+            let segment = (usize::BITS - bucket.leading_zeros()) as usize;
+            (segment, bucket - (1 << (segment - 1)))
+        }
+    }
+}
+
+fn is_seg<S, Q>(seg: &Q) -> impl Fn(&ListNode<S>) -> bool + '_
+where
+    S: Borrow<Q>,
+    Q: Eq,
+{
+    move |node| node.seg.as_ref().is_some_and(|key| key.borrow() == seg)
+}
@@ -0,0 +1,316 @@
+use crate::node::{Node, WithKey};
+use crate::state::FreezeGuard;
+use crate::store::{ChildLookup, ChildStore};
+use crate::{GuardedTrie, Trie};
+use crossbeam::epoch::{Guard, Shared};
+use crossbeam::utils::Backoff;
+use std::sync::atomic::Ordering::*;
+use std::sync::PoisonError;
+use std::{mem, ptr};
+
+/// The writes of a transaction run by [`Trie::transaction`], along with
+/// the values it read from the trie.
+#[derive(Debug)]
+pub struct Transaction<'t, S, V, H, C>
+where
+    C: ChildStore<S, H>,
+{
+    trie: &'t GuardedTrie<'t, S, V, H, C>,
+    reads: Vec<(Vec<S>, Option<&'t V>)>,
+    writes: Vec<(Vec<S>, Option<V>)>,
+}
+
+/// A node along with its version when it was recorded.
+type WithVersion<'g, S, V, H, C> = (&'g Node<S, V, H, C>, usize);
+
+/// Why a transaction failed to commit.
+enum Conflict {
+    /// A key that the transaction read holds another value by now.
+    Read,
+    /// The trie changed under the copies that the draft made of its
+    /// nodes.
+    Draft,
+}
+
+impl<S, V, H, C> Trie<S, V, H, C>
+where
+    S: Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    H: Clone,
+    C: ChildStore<S, H>,
+{
+    /// Runs `f` as a transaction, whose writes are seen by the readers
+    /// of the trie all at once, e.g. to move a value from one key to
+    /// another. Returns what `f` returns.
+    ///
+    /// `f` reads the trie through the [`Transaction`] passed to it,
+    /// which keeps the writes of `f` and shows them to its later reads.
+    /// The writes are applied once `f` returns, unless a key that `f`
+    /// read holds another value by then. On such a conflict, `f` is run
+    /// again, so it should have no other side effects. Writes to the
+    /// keys that `f` did not read do not conflict.
+    ///
+    /// The writes go to copies of the nodes on their paths, which the
+    /// commit then links into the trie at once. The copies are made
+    /// while the trie is written to, and a copy links to every child of
+    /// its original, like after a snapshot. The commit only holds off
+    /// the writers while it checks the reads and the copies, and swaps
+    /// the root. The nodes on the paths of other keys are left as they
+    /// are.
+    ///
+    /// If a writer changed one of the copied nodes in the meantime, `f`
+    /// is run again, and the copies are made while holding off the
+    /// writers. `f` runs outside of the commit, so it may access the
+    /// trie itself.
+    pub fn transaction<F, R>(&self, mut f: F) -> R
+    where
+        F: FnMut(&mut Transaction<'_, S, V, H, C>) -> R,
+    {
+        let backoff = Backoff::new();
+        let mut exclusive = false;
+
+        loop {
+            let trie = self.pin();
+            let mut tx = Transaction {
+                trie: &trie,
+                reads: vec![],
+                writes: vec![],
+            };
+
+            let result = f(&mut tx);
+            match tx.commit(exclusive) {
+                Ok(()) => break result,
+                Err(Conflict::Read) => {}
+                Err(Conflict::Draft) => exclusive = true,
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Makes the root of `draft` that of the trie, along with `len`.
+    /// The caller holds the gate exclusively, and moved the nodes of
+    /// the draft over to the generation of the trie.
+    fn install(&self, draft: Self, len: usize, guard: &Guard) {
+        let root = self.root.load(Acquire, guard);
+        let new_root = draft.root.swap(Shared::null(), Relaxed, guard);
+        self.root.store(new_root, Release);
+        self.len.store(len, Relaxed);
+
+        if !root.is_null() {
+            unsafe {
+                Node::release(root, guard);
+            }
+        }
+    }
+}
+
+impl<'t, S, V, H, C> Transaction<'t, S, V, H, C>
+where
+    S: Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    H: Clone,
+    C: ChildStore<S, H>,
+{
+    /// Returns the value at `key`, as written by the transaction, or
+    /// else as found in the trie.
+    pub fn get<'a, K>(&mut self, key: K) -> Option<&V>
+    where
+        K: IntoIterator<Item = &'a S>,
+        S: 'a,
+    {
+        let key: Vec<S> = key.into_iter().cloned().collect();
+        match self.written(&key) {
+            Some(i) => self.writes[i].1.as_ref(),
+            None => self.read(key),
+        }
+    }
+
+    /// Inserts `value` at `key` once the transaction commits.
+    pub fn insert<K>(&mut self, key: K, value: V)
+    where
+        K: IntoIterator<Item = S>,
+    {
+        let key: Vec<S> = key.into_iter().collect();
+        match self.written(&key) {
+            Some(i) => self.writes[i].1 = Some(value),
+            None => self.writes.push((key, Some(value))),
+        }
+    }
+
+    /// Removes the value at `key` once the transaction commits, and
+    /// returns it.
+    pub fn remove<'a, K>(&mut self, key: K) -> Option<V>
+    where
+        K: IntoIterator<Item = &'a S>,
+        S: 'a,
+    {
+        let key: Vec<S> = key.into_iter().cloned().collect();
+        match self.written(&key) {
+            Some(i) => self.writes[i].1.take(),
+            None => {
+                let value = self.read(key.clone()).cloned();
+                self.writes.push((key, None));
+                value
+            }
+        }
+    }
+
+    /// Returns the index of the write to `key`, if any.
+    fn written(&self, key: &[S]) -> Option<usize> {
+        self.writes.iter().position(|(written, _)| {
+            written.len() == key.len()
+                && written
+                    .iter()
+                    .zip(key)
+                    .all(|(seg, key_seg)| <C as ChildLookup<S, S>>::matches(seg, key_seg))
+        })
+    }
+
+    /// Reads the value at `key` from the trie, and records it to be
+    /// checked at the commit.
+    fn read(&mut self, key: Vec<S>) -> Option<&'t V> {
+        let trie = self.trie;
+        let value = trie.get(&key);
+        self.reads.push((key, value));
+        value
+    }
+
+    /// Applies the writes to the trie at once, unless a key read holds
+    /// another value by now.
+    ///
+    /// The reads are checked by the values found, which the copies of a
+    /// node hold as well, so that snapshots and other commits in the
+    /// meantime do not conflict. The guard of the transaction keeps the
+    /// values from being freed, so that no other value can take their
+    /// place.
+    ///
+    /// The writes go to a draft sharing the root of the trie, like a
+    /// snapshot. The nodes on their paths are recorded along with their
+    /// versions before the draft copies them, to tell whether they were
+    /// written to since. If `exclusive` is set, the writers are held off
+    /// from the start.
+    fn commit(mut self, exclusive: bool) -> Result<(), Conflict> {
+        let trie = self.trie.trie;
+        let guard = &self.trie.guard;
+        let gate = || trie.gate.write().unwrap_or_else(PoisonError::into_inner);
+        let held = exclusive.then(gate);
+
+        if self.writes.is_empty() {
+            let _gate = held.unwrap_or_else(gate);
+            return self.check_reads();
+        }
+
+        let draft = trie.share_root();
+        let base = draft.root.load(Acquire, guard);
+        let mut originals: Vec<WithVersion<S, V, H, C>> = vec![];
+        if let Some(root) = unsafe { base.as_ref() } {
+            for (key, _) in &self.writes {
+                for (node, _) in root.path_to(key, guard) {
+                    if !originals.iter().any(|(seen, _)| ptr::eq(*seen, node)) {
+                        originals.push((node, node.version()));
+                    }
+                }
+            }
+        }
+
+        // Each write is recorded along with the change in the number of
+        // values it makes.
+        let draft_guard = draft.pin();
+        let writes: Vec<(Vec<S>, isize)> = mem::take(&mut self.writes)
+            .into_iter()
+            .map(|(key, value)| {
+                let delta = match value {
+                    Some(value) => match draft_guard.insert(key.clone(), value) {
+                        Some(_) => 0,
+                        None => 1,
+                    },
+                    None => match draft_guard.remove(&key) {
+                        Some(_) => -1,
+                        None => 0,
+                    },
+                };
+                (key, delta)
+            })
+            .collect();
+        drop(draft_guard);
+
+        let _gate = held.unwrap_or_else(gate);
+        self.check_reads()?;
+        let is_current = trie.root.load(Acquire, guard) == base
+            && originals
+                .iter()
+                .all(|(node, version)| node.version() == *version);
+        if !is_current {
+            return Err(Conflict::Draft);
+        }
+
+        // The nodes of the draft on the paths of the writes are its
+        // copies and new nodes, along with the nodes it still shares.
+        let draft_gen = draft.gen.load(Relaxed);
+        let mut nodes: Vec<WithKey<S, V, H, C>> = vec![];
+        if let Some(root) = unsafe { draft.root.load(Acquire, guard).as_ref() } {
+            for (key, _) in &writes {
+                for (node, node_key) in root.path_to(key, guard) {
+                    if !nodes.iter().any(|(seen, _)| ptr::eq(*seen, node)) {
+                        nodes.push((node, node_key));
+                    }
+                }
+            }
+        }
+
+        // The copied nodes are retired, so that the writers holding on
+        // to them find the copies instead. Removed nodes are never
+        // written to again anyway.
+        let mut freezes = vec![];
+        for (node, _) in &originals {
+            if node.is_removed() || nodes.iter().any(|(kept, _)| ptr::eq(*kept, *node)) {
+                continue;
+            }
+            freezes.push(node.state.try_freeze().ok_or(Conflict::Draft)?);
+        }
+
+        // The counts of the draft missed the writes to the trie since
+        // the copies were made. A node counts the values of the trie
+        // under its key, along with those the transaction adds there.
+        for (node, node_key) in &nodes {
+            if node.gen() != draft_gen {
+                continue;
+            }
+
+            let added: isize = writes
+                .iter()
+                .filter(|(key, _)| has_prefix::<S, H, C>(key, node_key))
+                .map(|(_, delta)| delta)
+                .sum();
+            let count = self.trie.count_prefix(node_key) as isize + added;
+            node.count.store(count as usize, Relaxed);
+            node.set_gen(trie.gen.load(Relaxed));
+        }
+
+        let added: isize = writes.iter().map(|(_, delta)| delta).sum();
+        trie.install(draft, (trie.len() as isize + added) as usize, guard);
+        freezes.into_iter().for_each(FreezeGuard::retire);
+        Ok(())
+    }
+
+    /// Tells whether the keys read still hold the values found.
+    fn check_reads(&self) -> Result<(), Conflict> {
+        let is_valid = self.reads.iter().all(|(key, value)| {
+            let current = self.trie.get(key).map(|value| value as *const V);
+            current == value.map(|value| value as *const V)
+        });
+        is_valid.then_some(()).ok_or(Conflict::Read)
+    }
+}
+
+/// Tells whether `key` starts with `prefix`.
+fn has_prefix<S, H, C>(key: &[S], prefix: &[S]) -> bool
+where
+    C: ChildStore<S, H>,
+{
+    key.len() >= prefix.len()
+        && prefix
+            .iter()
+            .zip(key)
+            .all(|(seg, key_seg)| <C as ChildLookup<S, S>>::matches(seg, key_seg))
+}
@@ -0,0 +1,430 @@
+use chash_trie::store::{ChildStore, SkipListStore};
+use chash_trie::{OrderedTrie, Retry, Trie};
+use once_cell::sync::Lazy;
+use rand::prelude::*;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    ops::Bound,
+    sync::Arc,
+    thread::{available_parallelism, spawn},
+};
+
+static NUM_THREADS: Lazy<usize> = Lazy::new(|| available_parallelism().unwrap().get());
+
+#[test]
+fn long_key_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+    let key: Vec<u32> = (0..32).collect();
+
+    assert_eq!(guard.insert(key.clone(), 1), None);
+    assert_eq!(guard.get(&key), Some(&1));
+    assert_eq!(guard.get(&key[..31]), None);
+    assert_eq!(guard.get(&[0, 1, 3]), None);
+
+    // The whole key hangs off the root in a single leaf.
+    let leaf = guard.find(&key).unwrap();
+    assert_eq!(leaf.depth(), 32);
+    assert_eq!(leaf.parent().unwrap().depth(), 0);
+    assert_eq!(leaf.key(), Some(key.clone()));
+    assert!(guard.find(&key[..16]).is_none());
+
+    assert_eq!(guard.count_prefix(&key[..16]), 1);
+    assert_eq!(guard.iter_prefix(&key[..16]).count(), 1);
+    assert_eq!(guard.count_prefix(&[0, 2]), 0);
+    assert_eq!(guard.longest_prefix(&[&key[..], &[99]].concat()), Some((32, &1)));
+    assert_eq!(guard.longest_prefix(&key[..31]), None);
+    assert_eq!(guard.ancestors(&key).collect::<Vec<_>>(), vec![(32, &1)]);
+    assert_eq!(guard.iter_with_keys().collect::<Vec<_>>(), vec![(key.clone(), &1)]);
+
+    assert_eq!(guard.remove(&key), Some(&1));
+    assert!(guard.iter().next().is_none());
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn split_and_merge_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+
+    guard.insert("/usr/lib/libc.so".chars(), 1);
+    guard.insert("/usr/lib/libm.so".chars(), 2);
+    guard.insert("/usr/lib".chars(), 3);
+    guard.insert("/usr/lib/libc.so.6".chars(), 4);
+    guard.insert("/usr/bin/ls".chars(), 5);
+
+    let get = |key: &str| guard.get(&key.chars().collect::<Vec<_>>()).cloned();
+    assert_eq!(get("/usr/lib/libc.so"), Some(1));
+    assert_eq!(get("/usr/lib/libm.so"), Some(2));
+    assert_eq!(get("/usr/lib"), Some(3));
+    assert_eq!(get("/usr/lib/libc.so.6"), Some(4));
+    assert_eq!(get("/usr/bin/ls"), Some(5));
+    assert_eq!(get("/usr/lib/lib"), None);
+    assert_eq!(get("/usr"), None);
+
+    let count = |prefix: &str| guard.count_prefix(&prefix.chars().collect::<Vec<_>>());
+    assert_eq!(count("/usr/"), 5);
+    assert_eq!(count("/usr/lib"), 4);
+    assert_eq!(count("/usr/lib/libc"), 2);
+    assert_eq!(count("/usr/b"), 1);
+
+    let mut keys: Vec<String> = guard.keys().map(String::from_iter).collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        vec![
+            "/usr/bin/ls",
+            "/usr/lib",
+            "/usr/lib/libc.so",
+            "/usr/lib/libc.so.6",
+            "/usr/lib/libm.so",
+        ]
+    );
+
+    // Removing the branches merges the last leaf back into a single
+    // node below the root.
+    let remove = |key: &str| guard.remove(&key.chars().collect::<Vec<_>>()).cloned();
+    assert_eq!(remove("/usr/lib/libc.so"), Some(1));
+    assert_eq!(remove("/usr/lib"), Some(3));
+    assert_eq!(remove("/usr/bin/ls"), Some(5));
+    assert_eq!(remove("/usr/lib/libm.so"), Some(2));
+    assert_eq!(remove("/usr/lib/libm.so"), None);
+
+    let key: Vec<char> = "/usr/lib/libc.so.6".chars().collect();
+    let leaf = guard.find(&key).unwrap();
+    assert_eq!(leaf.parent().unwrap().depth(), 0);
+    assert_eq!(leaf.key(), Some(key.clone()));
+    assert_eq!(guard.get(&key), Some(&4));
+    assert_eq!(guard.count_prefix(&[] as &[char]), 1);
+    assert_eq!(trie.len(), 1);
+}
+
+#[test]
+fn remove_prefix_within_tail_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+
+    guard.insert([1u8, 2, 3, 4, 5], 1);
+    guard.insert([1, 2, 6], 2);
+
+    assert_eq!(guard.remove_prefix(&[1, 2, 3, 9]), 0);
+    assert_eq!(guard.remove_prefix(&[1, 2, 3]), 1);
+    assert_eq!(guard.get(&[1, 2, 3, 4, 5]), None);
+    assert_eq!(guard.get(&[1, 2, 6]), Some(&2));
+    assert_eq!(guard.remove_prefix(&[1]), 1);
+    assert!(guard.iter().next().is_none());
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn compressed_entry_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+
+    guard.insert(["tenant", "42", "session", "a"], 1);
+    let session = guard.find(&["tenant", "42", "session", "a"]).unwrap();
+    assert_eq!(session.depth(), 4);
+    assert_eq!(session.children().count(), 0);
+
+    // A node with a tail takes children as it is.
+    assert_eq!(session.insert_child("x", 2), Ok(None));
+    assert_eq!(guard.get(&["tenant", "42", "session", "a", "x"]), Some(&2));
+    assert_eq!(session.try_insert(3), Ok(Some(&1)));
+    assert_eq!(session.parent().unwrap().depth(), 0);
+    assert_eq!(session.insert_at(["y", "z"], 4), Ok(None));
+
+    let mut children: Vec<_> = session
+        .children()
+        .map(|(seg, child)| (seg, child.depth(), child.get().cloned()))
+        .collect();
+    children.sort();
+    assert_eq!(children, vec![("x", 5, Some(2)), ("y", 6, Some(4))]);
+    assert_eq!(
+        session.child(&"y").unwrap().key(),
+        Some(vec!["tenant", "42", "session", "a", "y", "z"])
+    );
+    assert!(session.find(&["y"]).is_none());
+    assert_eq!(session.find(&["y", "z"]).unwrap().get(), Some(&4));
+
+    // A key leading off the tail splits it, which relinks the node.
+    guard.insert(["tenant", "42", "other"], 5);
+    assert_eq!(session.try_insert(6), Err(Retry(6)));
+    assert_eq!(session.remove(), None);
+
+    let session = guard.find(&["tenant", "42", "session", "a"]).unwrap();
+    assert_eq!(session.parent().unwrap().depth(), 2);
+    assert_eq!(session.get(), Some(&3));
+    assert_eq!(session.children().count(), 2);
+
+    assert_eq!(guard.count_prefix(&["tenant"]), 4);
+    assert_eq!(guard.remove_prefix(&["tenant", "42", "session"]), 3);
+
+    // The node left with a single child is merged away.
+    let other = guard.find(&["tenant", "42", "other"]).unwrap();
+    assert_eq!(other.parent().unwrap().depth(), 0);
+    assert_eq!(other.remove(), Some(&5));
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn internal_run_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+    let key = |key: &str| key.chars().collect::<Vec<_>>();
+
+    // The two keys branch off below a single node, which keeps the
+    // segments they share as its tail.
+    guard.insert("/var/log/syslog".chars(), 1);
+    guard.insert("/var/log/auth.log".chars(), 2);
+    let branch = guard.find(&key("/var/log/")).unwrap();
+    assert_eq!(branch.depth(), 9);
+    assert_eq!(branch.parent().unwrap().depth(), 0);
+    assert_eq!(branch.children().count(), 2);
+    assert!(guard.find(&key("/var")).is_none());
+
+    // Keys leading off or ending within the tail split it once each.
+    guard.insert("/var/lib/dpkg".chars(), 3);
+    guard.insert("/var".chars(), 4);
+    let branch = guard.find(&key("/var/log/")).unwrap();
+    assert_eq!(branch.parent().unwrap().depth(), 6);
+    assert_eq!(branch.parent().unwrap().parent().unwrap().depth(), 4);
+    assert_eq!(branch.key(), Some(key("/var/log/")));
+    assert_eq!(guard.get(&key("/var")), Some(&4));
+    assert_eq!(guard.get(&key("/var/l")), None);
+    assert_eq!(guard.count_prefix(&key("/var/l")), 3);
+    assert_eq!(guard.count_prefix(&key("/var/lo")), 2);
+
+    let mut keys: Vec<String> = guard.keys().map(String::from_iter).collect();
+    keys.sort();
+    assert_eq!(keys, ["/var", "/var/lib/dpkg", "/var/log/auth.log", "/var/log/syslog"]);
+
+    // Removing the keys merges the nodes back.
+    assert_eq!(guard.remove(&key("/var")), Some(&4));
+    assert_eq!(guard.remove(&key("/var/lib/dpkg")), Some(&3));
+    let branch = guard.find(&key("/var/log/")).unwrap();
+    assert_eq!(branch.parent().unwrap().depth(), 0);
+    assert_eq!(guard.count_prefix(&key("/var/log/")), 2);
+    assert_eq!(trie.len(), 2);
+}
+
+#[test]
+fn ordered_range_test() {
+    let trie = OrderedTrie::<u8, usize>::new().compressed();
+    let guard = trie.pin();
+    let mut model = BTreeMap::new();
+    let mut rng = StdRng::seed_from_u64(20);
+
+    for i in 0..500 {
+        let len = rng.gen_range(0..8);
+        let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..3)).collect();
+        guard.insert(key.clone(), i);
+        model.insert(key, i);
+    }
+
+    let expected: Vec<_> = model.iter().map(|(key, value)| (key.clone(), value)).collect();
+    assert_eq!(guard.iter_with_keys().collect::<Vec<_>>(), expected);
+
+    for _ in 0..200 {
+        let len = rng.gen_range(0..8);
+        let from: Vec<u8> = (0..len).map(|_| rng.gen_range(0..3)).collect();
+        let expected: Vec<_> = model
+            .range::<[u8], _>((Bound::Excluded(&from[..]), Bound::Unbounded))
+            .map(|(key, value)| (key.clone(), value))
+            .collect();
+        let scanned: Vec<_> = guard
+            .range::<[u8], _>((Bound::Excluded(&from[..]), Bound::Unbounded))
+            .collect();
+        assert_eq!(scanned, expected);
+    }
+}
+
+/// Runs random inserts and removals against a model, checking every
+/// lookup along the way.
+fn check_model<C>(trie: Trie<u8, u32, RandomState, C>)
+where
+    C: ChildStore<u8, RandomState>,
+{
+    let guard = trie.pin();
+    let mut model = HashMap::new();
+    let mut rng = StdRng::seed_from_u64(7);
+
+    for i in 0..5000 {
+        let len = rng.gen_range(0..10);
+        let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..3)).collect();
+
+        match rng.gen_range(0..10) {
+            0..=4 => assert_eq!(guard.insert(key.clone(), i), model.insert(key.clone(), i).as_ref()),
+            5..=7 => assert_eq!(guard.remove(&key), model.remove(&key).as_ref()),
+            8 => {
+                let removed = guard.remove_prefix(&key);
+                let len = model.len();
+                model.retain(|model_key, _| !model_key.starts_with(&key));
+                assert_eq!(removed, len - model.len());
+            }
+            _ => {
+                let count = model.keys().filter(|k| k.starts_with(&key)).count();
+                assert_eq!(guard.count_prefix(&key), count);
+                assert_eq!(guard.iter_prefix(&key).count(), count);
+            }
+        }
+        assert_eq!(guard.get(&key), model.get(&key));
+    }
+
+    assert_eq!(trie.len(), model.len());
+    let mut keys: Vec<_> = guard.keys().collect();
+    let mut expected: Vec<_> = model.keys().cloned().collect();
+    keys.sort();
+    expected.sort();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn model_test() {
+    check_model(Trie::new().compressed());
+    check_model(Trie::<u8, u32, _, SkipListStore<u8>>::with_store().compressed());
+}
+
+/// Races writers and removers on long keys sharing prefixes, so that
+/// tails are split and merged all the time, and checks that the counts
+/// agree with the values left behind.
+#[test]
+fn concurrent_split_merge_test() {
+    let trie = Arc::new(Trie::new().compressed());
+
+    let handles: Vec<_> = (0..(*NUM_THREADS).max(4))
+        .map(|_| {
+            let trie = trie.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..20_000 {
+                    let len = rng.gen_range(1..12);
+                    let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..2)).collect();
+                    let guard = trie.pin();
+                    match rng.gen_range(0..10) {
+                        0..=4 => {
+                            guard.insert(key, len);
+                        }
+                        5..=8 => {
+                            guard.remove(&key);
+                        }
+                        _ => {
+                            guard.remove_prefix(&key[..len / 2]);
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let guard = trie.pin();
+    let keys: Vec<Vec<u8>> = guard.keys().collect();
+    assert_eq!(trie.len(), keys.len());
+    assert_eq!(guard.count_prefix(&[] as &[u8]), keys.len());
+    for key in &keys {
+        assert_eq!(guard.get(key), Some(&key.len()));
+        for len in 0..key.len() {
+            let prefix = &key[..len];
+            let count = keys.iter().filter(|k| k.starts_with(prefix)).count();
+            assert_eq!(guard.count_prefix(prefix), count);
+        }
+    }
+}
+
+/// Races inserts of keys that keep splitting the same leaf against
+/// lookups of a key that is present throughout.
+#[test]
+fn concurrent_split_get_test() {
+    let trie = Arc::new(Trie::new().compressed());
+    let key: Vec<u32> = (0..32).collect();
+    trie.pin().insert(key.clone(), u32::MAX);
+
+    let writers: Vec<_> = (0..(*NUM_THREADS).max(2))
+        .map(|t| {
+            let trie = trie.clone();
+            let key = key.clone();
+            spawn(move || {
+                for i in (t..32).step_by(2) {
+                    let mut branch = key[..i].to_vec();
+                    branch.push(100 + t as u32);
+                    trie.pin().insert(branch.clone(), i as u32);
+                    assert_eq!(trie.pin().remove(&branch), Some(&(i as u32)));
+                }
+            })
+        })
+        .collect();
+
+    let reader = {
+        let trie = trie.clone();
+        let key = key.clone();
+        spawn(move || {
+            for _ in 0..10_000 {
+                assert_eq!(trie.pin().get(&key), Some(&u32::MAX));
+            }
+        })
+    };
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    reader.join().unwrap();
+
+    let guard = trie.pin();
+    assert_eq!(guard.get(&key), Some(&u32::MAX));
+    assert_eq!(trie.len(), 1);
+}
+
+/// Races writers below a node with a long tail against writers that
+/// keep splitting the tail and merging it back, and checks that the
+/// counts agree with the values left behind.
+#[test]
+fn concurrent_internal_split_test() {
+    let trie = Arc::new(Trie::new().compressed());
+    let prefix: Vec<u32> = (0..24).collect();
+
+    let handles: Vec<_> = (0..(*NUM_THREADS).max(4))
+        .map(|t| {
+            let trie = trie.clone();
+            let prefix = prefix.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+                for i in 0..10_000u32 {
+                    let guard = trie.pin();
+                    if t % 2 == 0 {
+                        let key = [&prefix[..], &[t as u32, i % 50]].concat();
+                        let replaced = match rng.gen_range(0..3) {
+                            0 => guard.remove(&key),
+                            _ => guard.insert(key, i % 50),
+                        };
+                        assert!(replaced.map_or(true, |value| *value == i % 50));
+                    } else {
+                        let mut branch = prefix[..rng.gen_range(1..24)].to_vec();
+                        branch.push(100 + t as u32);
+                        guard.insert(branch.clone(), 0);
+                        assert_eq!(guard.remove(&branch), Some(&0));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let guard = trie.pin();
+    let keys: Vec<Vec<u32>> = guard.keys().collect();
+    assert_eq!(trie.len(), keys.len());
+    assert!(keys.iter().all(|key| key.starts_with(&prefix)));
+    for len in 0..=prefix.len() {
+        assert_eq!(guard.count_prefix(&prefix[..len]), keys.len());
+    }
+    for t in 0..(*NUM_THREADS).max(4) as u32 {
+        let below = [&prefix[..], &[t]].concat();
+        let count = keys.iter().filter(|key| key.starts_with(&below)).count();
+        assert_eq!(guard.count_prefix(&below), count);
+    }
+}
@@ -0,0 +1,234 @@
+use chash_trie::store::{
+    ArtStore, ByteStore, ChildStore, DashStore, SkipListStore, SplitOrderedStore,
+};
+use chash_trie::Trie;
+use crossbeam::epoch;
+use std::{
+    collections::hash_map::RandomState,
+    sync::{
+        atomic::{AtomicUsize, Ordering::*},
+        Arc,
+    },
+    thread::yield_now,
+};
+
+#[derive(Debug)]
+struct Tracked {
+    live: Arc<AtomicUsize>,
+}
+
+impl Tracked {
+    fn new(live: &Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, SeqCst);
+        Self { live: live.clone() }
+    }
+}
+
+impl Clone for Tracked {
+    fn clone(&self) -> Self {
+        Self::new(&self.live)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, SeqCst);
+    }
+}
+
+/// Drives the global epoch forward until the number of live values
+/// drops to `expect`.
+fn settle(live: &AtomicUsize, expect: usize) -> usize {
+    for _ in 0..100_000 {
+        if live.load(SeqCst) == expect {
+            break;
+        }
+        epoch::pin().flush();
+        yield_now();
+    }
+    live.load(SeqCst)
+}
+
+#[test]
+fn overwrite_reclaim_test() {
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::new();
+
+    for _ in 0..1000 {
+        trie.pin().insert([1u8, 2, 3], Tracked::new(&live));
+    }
+
+    assert_eq!(settle(&live, 1), 1);
+
+    drop(trie);
+    assert_eq!(live.load(SeqCst), 0);
+}
+
+#[test]
+fn remove_reclaim_test() {
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::new();
+
+    for i in 0..100u8 {
+        trie.pin().insert([i, i, i], Tracked::new(&live));
+    }
+
+    for i in 0..100u8 {
+        assert!(trie.pin().remove(&[i, i, i]).is_some());
+    }
+
+    assert_eq!(settle(&live, 0), 0);
+    assert!(trie.pin().iter().next().is_none());
+}
+
+#[test]
+fn drop_trie_test() {
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::new();
+
+    for i in 0..10u8 {
+        for j in 0..10u8 {
+            trie.pin().insert([i, j], Tracked::new(&live));
+        }
+        trie.pin().insert([i], Tracked::new(&live));
+    }
+    assert_eq!(live.load(SeqCst), 110);
+
+    drop(trie);
+    assert_eq!(live.load(SeqCst), 0);
+}
+
+#[test]
+fn remove_prefix_reclaim_test() {
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::new();
+
+    for i in 0..10u8 {
+        for j in 0..10u8 {
+            trie.pin().insert([0, i, j], Tracked::new(&live));
+        }
+    }
+    trie.pin().insert([1], Tracked::new(&live));
+
+    assert_eq!(trie.pin().remove_prefix(&[0]), 100);
+    assert_eq!(settle(&live, 1), 1);
+}
+
+#[test]
+fn wide_fanout_reclaim_test() {
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::new();
+
+    for i in 0..100u8 {
+        trie.pin().insert([0, i], Tracked::new(&live));
+    }
+    for i in 3..100u8 {
+        assert!(trie.pin().remove(&[0, i]).is_some());
+    }
+    assert_eq!(settle(&live, 3), 3);
+
+    drop(trie);
+    assert_eq!(live.load(SeqCst), 0);
+}
+
+/// Removes and drops values from a trie with the store `C`, and checks
+/// that the values and their nodes are all freed.
+fn check_store_reclaim<C>()
+where
+    C: ChildStore<u8, RandomState>,
+{
+    let live = Arc::new(AtomicUsize::new(0));
+    let trie = Trie::<u8, _, _, C>::with_store();
+
+    for i in 0..100u8 {
+        trie.pin().insert([0, i], Tracked::new(&live));
+        trie.pin().insert([1, i, i], Tracked::new(&live));
+    }
+    for i in 3..100u8 {
+        assert!(trie.pin().remove(&[0, i]).is_some());
+    }
+    assert_eq!(trie.pin().remove_prefix(&[1, 5]), 1);
+    assert_eq!(settle(&live, 102), 102);
+
+    drop(trie);
+    assert_eq!(live.load(SeqCst), 0);
+}
+
+#[test]
+fn store_reclaim_test() {
+    check_store_reclaim::<DashStore<u8>>();
+    check_store_reclaim::<SplitOrderedStore<u8>>();
+    check_store_reclaim::<SkipListStore<u8>>();
+    check_store_reclaim::<ByteStore>();
+    check_store_reclaim::<ArtStore>();
+}
+
+#[test]
+fn snapshot_reclaim_test() {
+    for compressed in [false, true] {
+        let live = Arc::new(AtomicUsize::new(0));
+        let trie = match compressed {
+            false => Trie::new(),
+            true => Trie::new().compressed(),
+        };
+
+        for i in 0..10u8 {
+            for j in 0..10u8 {
+                trie.pin().insert([i, j, j], Tracked::new(&live));
+            }
+        }
+
+        // The copies on the paths written to hold clones of the values.
+        let snapshot = trie.snapshot();
+        let read_only = trie.read_only_snapshot();
+        for i in 0..10u8 {
+            trie.pin().insert([i, 0, 0], Tracked::new(&live));
+            snapshot.pin().remove(&[i, 1, 1]);
+        }
+        assert_eq!(trie.pin().remove_prefix(&[9]), 10);
+
+        // The trie holds nine values of its own, and the snapshots hold
+        // the rest, the removed ones among them.
+        assert_eq!(settle(&live, 109), 109);
+        drop(trie);
+        assert_eq!(settle(&live, 100), 100);
+
+        // The values the writable snapshot removed were left to the
+        // read-only one.
+        drop(read_only);
+        assert_eq!(settle(&live, 90), 90);
+        drop(snapshot);
+        assert_eq!(settle(&live, 0), 0);
+    }
+}
+
+#[test]
+fn transaction_reclaim_test() {
+    for compressed in [false, true] {
+        let live = Arc::new(AtomicUsize::new(0));
+        let trie = match compressed {
+            false => Trie::new(),
+            true => Trie::new().compressed(),
+        };
+
+        for i in 0..10u8 {
+            for j in 0..10u8 {
+                trie.pin().insert([i, j, j], Tracked::new(&live));
+            }
+        }
+
+        // The nodes replaced by the commit are released, along with the
+        // values moved away from them and the clones in the draft.
+        trie.transaction(|tx| {
+            for i in 0..10u8 {
+                let value = tx.remove(&[i, 1, 1]).unwrap();
+                tx.insert([i, 1, 2], value);
+            }
+        });
+        assert_eq!(trie.len(), 100);
+        assert_eq!(settle(&live, 100), 100);
+
+        drop(trie);
+        assert_eq!(settle(&live, 0), 0);
+    }
+}
@@ -0,0 +1,275 @@
+use chash_trie::store::SkipListStore;
+use chash_trie::{KeyEntry, OrderedTrie, Retry, Trie};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering::*},
+        Arc,
+    },
+    thread::{available_parallelism, spawn},
+};
+
+static NUM_THREADS: Lazy<usize> = Lazy::new(|| available_parallelism().unwrap().get());
+
+fn sorted<V: Ord>(iter: impl Iterator<Item = (Vec<u8>, V)>) -> Vec<(Vec<u8>, V)> {
+    let mut entries: Vec<_> = iter.collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn snapshot_test() {
+    for trie in [Trie::new(), Trie::new().compressed()] {
+        let guard = trie.pin();
+        for i in 0..10u8 {
+            guard.insert([i], i as u32);
+            guard.insert([i, i], 10 * i as u32);
+        }
+        let before = sorted(guard.iter_with_keys().map(|(key, value)| (key, *value)));
+
+        let snapshot = trie.snapshot();
+        guard.insert([0], 100);
+        guard.insert([1, 2, 3], 123);
+        guard.remove(&[2]);
+        guard.remove(&[3, 3]);
+        assert_eq!(guard.remove_prefix(&[4]), 2);
+        guard.update([5], |value| value.map(|value| value + 1));
+        guard.compare_and_swap(&[6], &6, 60).unwrap();
+
+        let snapshot_guard = snapshot.pin();
+        let after = sorted(
+            snapshot_guard
+                .iter_with_keys()
+                .map(|(key, value)| (key, *value)),
+        );
+        assert_eq!(after, before);
+        assert_eq!(snapshot.len(), 20);
+        assert_eq!(snapshot_guard.get(&[0]), Some(&0));
+        assert_eq!(snapshot_guard.get(&[1, 2, 3]), None);
+        assert_eq!(snapshot_guard.count_prefix(&[4]), 2);
+        assert_eq!(snapshot_guard.get(&[5]), Some(&5));
+        assert_eq!(snapshot_guard.get(&[6]), Some(&6));
+
+        assert_eq!(trie.len(), 17);
+        assert_eq!(guard.iter().count(), 17);
+        assert_eq!(guard.get(&[0]), Some(&100));
+        assert_eq!(guard.get(&[1, 2, 3]), Some(&123));
+        assert_eq!(guard.get(&[2]), None);
+        assert_eq!(guard.get(&[3, 3]), None);
+        assert_eq!(guard.count_prefix(&[4]), 0);
+        assert_eq!(guard.get(&[5]), Some(&6));
+        assert_eq!(guard.get(&[6]), Some(&60));
+    }
+}
+
+#[test]
+fn writable_snapshot_test() {
+    let trie = Trie::new().compressed();
+    let guard = trie.pin();
+    guard.insert("/usr/lib/libc.so".chars(), 1);
+    guard.insert("/usr/bin/ls".chars(), 2);
+
+    let snapshot = trie.snapshot();
+    let snapshot_guard = snapshot.pin();
+    let get = |guard: &chash_trie::GuardedTrie<'_, char, _, _, _>, key: &str| {
+        guard.get(&key.chars().collect::<Vec<_>>()).cloned()
+    };
+
+    // Splitting and merging tails on either side leaves the other be.
+    snapshot_guard.insert("/usr/lib/libm.so".chars(), 3);
+    guard.insert("/usr/bin/cat".chars(), 4);
+    assert_eq!(
+        guard.remove(&"/usr/lib/libc.so".chars().collect::<Vec<_>>()),
+        Some(&1)
+    );
+
+    assert_eq!(get(&guard, "/usr/lib/libc.so"), None);
+    assert_eq!(get(&guard, "/usr/lib/libm.so"), None);
+    assert_eq!(get(&guard, "/usr/bin/cat"), Some(4));
+    assert_eq!(get(&guard, "/usr/bin/ls"), Some(2));
+    assert_eq!(trie.len(), 2);
+
+    assert_eq!(get(&snapshot_guard, "/usr/lib/libc.so"), Some(1));
+    assert_eq!(get(&snapshot_guard, "/usr/lib/libm.so"), Some(3));
+    assert_eq!(get(&snapshot_guard, "/usr/bin/cat"), None);
+    assert_eq!(get(&snapshot_guard, "/usr/bin/ls"), Some(2));
+    assert_eq!(snapshot.len(), 3);
+
+    // A snapshot of a snapshot outlives both.
+    let nested = snapshot.snapshot();
+    drop(snapshot_guard);
+    drop(snapshot);
+    drop(guard);
+    drop(trie);
+    assert_eq!(nested.pin().iter().count(), 3);
+}
+
+#[test]
+fn read_only_snapshot_test() {
+    let trie = OrderedTrie::<u8, u32>::new();
+    let guard = trie.pin();
+    for i in 0..100u8 {
+        guard.insert([i / 10, i % 10], i as u32);
+    }
+
+    let snapshot = trie.read_only_snapshot();
+    for i in 0..100u8 {
+        match i % 2 {
+            0 => guard.insert([i / 10, i % 10], 0),
+            _ => guard.remove(&[i / 10, i % 10]),
+        };
+    }
+    assert_eq!(trie.len(), 50);
+
+    let snapshot_guard = snapshot.pin();
+    assert_eq!(snapshot.len(), 100);
+    assert_eq!(snapshot_guard.get(&[4, 5]), Some(&45));
+    assert_eq!(snapshot_guard.longest_prefix(&[4, 5, 6]), Some((2, &45)));
+    assert_eq!(snapshot_guard.count_prefix(&[4]), 10);
+    assert_eq!(snapshot_guard.iter_prefix(&[4]).count(), 10);
+    let values: Vec<_> = snapshot_guard
+        .range(&[2, 5][..]..&[3, 2][..])
+        .map(|(_, value)| *value)
+        .collect();
+    assert_eq!(values, (25..32).collect::<Vec<_>>());
+}
+
+#[test]
+fn shared_value_snapshot_test() {
+    // The values need not be `Clone`, as the copies of the nodes hold
+    // the same ones.
+    #[derive(Debug, PartialEq)]
+    struct Value(u32);
+
+    let trie = Trie::new();
+    let guard = trie.pin();
+    guard.insert([0], Value(0));
+    guard.insert([0, 1], Value(1));
+
+    let snapshot = trie.snapshot();
+    guard.insert([0, 1, 2], Value(2));
+    guard.insert([0, 1], Value(10));
+
+    let snapshot_guard = snapshot.pin();
+    let value = guard.get(&[0]).unwrap();
+    assert!(ptr::eq(value, snapshot_guard.get(&[0]).unwrap()));
+    assert_eq!(snapshot_guard.get(&[0, 1]), Some(&Value(1)));
+    assert_eq!(snapshot_guard.get(&[0, 1, 2]), None);
+    assert_eq!(guard.get(&[0, 1]), Some(&Value(10)));
+
+    drop(guard);
+    drop(trie);
+    assert_eq!(snapshot_guard.get(&[0]), Some(&Value(0)));
+}
+
+#[test]
+fn entry_snapshot_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+    guard.insert([1, 2], 1);
+
+    let entry = guard.find(&[1, 2]).unwrap();
+    let key_entry = guard.entry([1, 2]);
+    let _snapshot = trie.snapshot();
+
+    // The entries reached before the snapshot sit on shared nodes.
+    assert_eq!(entry.get(), Some(&1));
+    assert!(matches!(entry.try_insert(2), Err(Retry(2))));
+    assert_eq!(entry.remove(), None);
+
+    let entry = guard.find(&[1, 2]).unwrap();
+    assert_eq!(entry.try_insert(3), Ok(Some(&1)));
+
+    // A key entry finds the copy of its node, with a clone of the
+    // value it saw.
+    match key_entry.and_modify(|value| value + 10) {
+        KeyEntry::Occupied(entry) => assert_eq!(entry.get(), &13),
+        KeyEntry::Vacant(_) => panic!("the key holds a value"),
+    }
+    assert_eq!(guard.get(&[1, 2]), Some(&13));
+}
+
+#[test]
+fn snapshot_within_update_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+    guard.insert([1u8], 1);
+
+    // The closures run outside of the write, and run again on the copy
+    // of the node once a snapshot is taken in between.
+    let mut snapshot = None;
+    let replaced = guard.update([1], |value| {
+        snapshot.get_or_insert_with(|| trie.snapshot());
+        value.map(|value| value + 1)
+    });
+    assert_eq!(replaced, Some(&1));
+    assert_eq!(guard.get(&[1]), Some(&2));
+    assert_eq!(snapshot.unwrap().pin().get(&[1]), Some(&1));
+
+    let mut snapshot = None;
+    match guard.entry([1]).and_modify(|value| {
+        snapshot.get_or_insert_with(|| trie.snapshot());
+        value + 10
+    }) {
+        KeyEntry::Occupied(entry) => assert_eq!(entry.get(), &12),
+        KeyEntry::Vacant(_) => panic!("the key holds a value"),
+    }
+    assert_eq!(guard.get(&[1]), Some(&12));
+    assert_eq!(snapshot.unwrap().pin().get(&[1]), Some(&2));
+}
+
+#[test]
+fn concurrent_snapshot_test() {
+    let num_keys = 2000u16;
+    let trie = Arc::new(Trie::<u16, u16, _, SkipListStore<u16>>::with_store().compressed());
+    let done = Arc::new(AtomicBool::new(false));
+
+    // Each writer inserts its keys in order, and then removes them in
+    // order, so that any consistent view holds a run of them.
+    let writers: Vec<_> = (0..*NUM_THREADS as u16)
+        .map(|t| {
+            let trie = trie.clone();
+            spawn(move || {
+                let guard = trie.pin();
+                for i in 0..num_keys {
+                    guard.insert([t, i / 100, i], i);
+                }
+                for i in 0..num_keys {
+                    assert_eq!(guard.remove(&[t, i / 100, i]), Some(&i));
+                }
+            })
+        })
+        .collect();
+
+    let reader = {
+        let trie = trie.clone();
+        let done = done.clone();
+        spawn(move || {
+            while !done.load(Acquire) {
+                let snapshot = trie.read_only_snapshot();
+                let guard = snapshot.pin();
+                let mut keys = BTreeMap::<u16, Vec<u16>>::new();
+                for (key, value) in guard.iter_with_keys() {
+                    assert_eq!(key[2], *value);
+                    keys.entry(key[0]).or_default().push(key[2]);
+                }
+
+                assert_eq!(keys.values().map(Vec::len).sum::<usize>(), snapshot.len());
+                for (t, mut keys) in keys {
+                    keys.sort_unstable();
+                    assert!(keys.windows(2).all(|pair| pair[1] == pair[0] + 1));
+                    assert_eq!(guard.count_prefix(&[t]), keys.len());
+                }
+            }
+        })
+    };
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    done.store(true, Release);
+    reader.join().unwrap();
+    assert!(trie.is_empty());
+}
@@ -0,0 +1,404 @@
+use chash_trie::store::{
+    AdaptiveStore, ArtStore, ByteStore, ChildStore, DashStore, OrderedStore, SkipListStore,
+    SplitOrderedStore,
+};
+use chash_trie::{ByteTrie, OrderedTrie, Trie};
+use once_cell::sync::Lazy;
+use rand::prelude::*;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    ops::Bound,
+    sync::Arc,
+    thread::{available_parallelism, spawn},
+};
+
+static NUM_THREADS: Lazy<usize> = Lazy::new(|| available_parallelism().unwrap().get());
+
+/// Runs inserts, lookups and removals on a trie with a wide fanout.
+fn check_store<C>()
+where
+    C: ChildStore<u8, RandomState>,
+{
+    let trie = Trie::<u8, u32, _, C>::with_store();
+
+    {
+        let trie = trie.pin();
+        for a in 0..=255u8 {
+            for b in 0..4u8 {
+                assert_eq!(trie.insert([a, b], a as u32 * 4 + b as u32), None);
+            }
+        }
+        assert_eq!(trie.insert([7, 1], 0), Some(&29));
+        trie.insert([7, 1], 29);
+    }
+    assert_eq!(trie.len(), 1024);
+
+    let trie_ref = trie.pin();
+    for a in 0..=255u8 {
+        for b in 0..4u8 {
+            assert_eq!(trie_ref.get(&[a, b]), Some(&(a as u32 * 4 + b as u32)));
+        }
+        assert_eq!(trie_ref.get(&[a]), None);
+        assert_eq!(trie_ref.count_prefix(&[a]), 4);
+    }
+    assert_eq!(trie_ref.iter().count(), 1024);
+    drop(trie_ref);
+
+    {
+        let trie = trie.pin();
+        for a in (0..=255u8).step_by(2) {
+            for b in 0..4u8 {
+                assert!(trie.remove(&[a, b]).is_some());
+            }
+        }
+        assert_eq!(trie.remove(&[0, 0]), None);
+        assert_eq!(trie.remove_prefix(&[1]), 4);
+    }
+    assert_eq!(trie.len(), 508);
+
+    let trie_ref = trie.pin();
+    assert_eq!(trie_ref.iter().count(), 508);
+    assert!(trie_ref.find(&[0]).is_none());
+    assert_eq!(trie_ref.keys().filter(|key| key[0] % 2 == 0).count(), 0);
+
+    // Removed children come back.
+    trie_ref.insert([0, 0], 1);
+    trie_ref.insert([1, 0], 2);
+    assert_eq!(trie_ref.get(&[0, 0]), Some(&1));
+    assert_eq!(trie_ref.get(&[1, 0]), Some(&2));
+    assert_eq!(trie_ref.count_prefix(&[] as &[u8]), 510);
+}
+
+/// Races writers on a small key space, and checks that the counts
+/// agree with the values left behind.
+fn check_store_concurrent<C>()
+where
+    C: ChildStore<u8, RandomState> + Send + Sync + 'static,
+{
+    let trie = Arc::new(Trie::<u8, u32, _, C>::with_store());
+
+    let handles: Vec<_> = (0..(*NUM_THREADS).max(4))
+        .map(|_| {
+            let trie = trie.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+
+                for _ in 0..20_000 {
+                    let len = rng.gen_range(0..4);
+                    let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..3)).collect();
+                    let trie = trie.pin();
+
+                    match rng.gen_range(0..5) {
+                        0 | 1 => {
+                            trie.insert(key, 1);
+                        }
+                        2 => {
+                            trie.remove(&key);
+                        }
+                        3 => {
+                            trie.remove_prefix(&key[..key.len().min(1)]);
+                        }
+                        _ => {
+                            trie.get(&key);
+                            trie.iter().count();
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let trie_ref = trie.pin();
+    assert_eq!(trie.len(), trie_ref.iter().count());
+    for a in 0..3u8 {
+        assert_eq!(
+            trie_ref.count_prefix(&[a]),
+            trie_ref.iter_prefix(&[a]).count()
+        );
+    }
+}
+
+#[test]
+fn adaptive_store_test() {
+    check_store::<AdaptiveStore<u8>>();
+    check_store_concurrent::<AdaptiveStore<u8>>();
+}
+
+#[test]
+fn dash_store_test() {
+    check_store::<DashStore<u8>>();
+    check_store_concurrent::<DashStore<u8>>();
+}
+
+#[test]
+fn split_ordered_store_test() {
+    check_store::<SplitOrderedStore<u8>>();
+    check_store_concurrent::<SplitOrderedStore<u8>>();
+}
+
+#[test]
+fn skip_list_store_test() {
+    check_store::<SkipListStore<u8>>();
+    check_store_concurrent::<SkipListStore<u8>>();
+}
+
+#[test]
+fn byte_store_test() {
+    check_store::<ByteStore>();
+    check_store_concurrent::<ByteStore>();
+}
+
+#[test]
+fn art_store_test() {
+    check_store::<ArtStore>();
+    check_store_concurrent::<ArtStore>();
+}
+
+#[test]
+fn art_resize_test() {
+    let trie = ByteTrie::<u32>::new();
+    let trie = trie.pin();
+    let mut rng = rand::thread_rng();
+    let mut bytes: Vec<u8> = (0..=255).collect();
+
+    // A single node grows through all sizes and shrinks back, while its
+    // keys stay in order.
+    bytes.shuffle(&mut rng);
+    for (i, &byte) in bytes.iter().enumerate() {
+        assert_eq!(trie.insert([byte], byte as u32), None);
+        let mut expected: Vec<_> = bytes[..=i].iter().map(|&byte| vec![byte]).collect();
+        expected.sort();
+        assert_eq!(trie.keys().collect::<Vec<_>>(), expected);
+    }
+
+    bytes.shuffle(&mut rng);
+    for (i, byte) in bytes.iter().enumerate() {
+        assert_eq!(trie.remove(&[*byte]), Some(&(*byte as u32)));
+        assert_eq!(trie.get(&[*byte]), None);
+        assert!(bytes[i + 1..]
+            .iter()
+            .all(|&b| trie.get(&[b]) == Some(&(b as u32))));
+    }
+    assert!(trie.iter().next().is_none());
+}
+
+#[test]
+fn art_concurrent_resize_test() {
+    let trie = Arc::new(ByteTrie::<u32>::new());
+    trie.pin().insert([0, 0], 0);
+
+    // Writers grow and shrink the nodes on the path to a key that is
+    // there throughout, while readers look it up.
+    let handles: Vec<_> = (0..(*NUM_THREADS).max(4))
+        .map(|t| {
+            let trie = trie.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+
+                for _ in 0..20_000 {
+                    let trie = trie.pin();
+                    if t % 2 == 0 {
+                        assert_eq!(trie.get(&[0, 0]), Some(&0));
+                        continue;
+                    }
+
+                    let key = [rng.gen_range(0..2), rng.gen_range(1..=255)];
+                    if rng.gen() {
+                        trie.insert(key, 1);
+                    } else {
+                        trie.remove(&key);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let trie_ref = trie.pin();
+    assert_eq!(trie.len(), trie_ref.iter().count());
+    let keys: Vec<_> = trie_ref.keys().collect();
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn adaptive_concurrent_resize_test() {
+    let trie = Arc::new(Trie::<String, u32>::new());
+    let key = |a: u32, b: u32| [a.to_string(), b.to_string()];
+    trie.pin().insert(key(0, 0), 0);
+
+    // Writers promote and demote the children of the nodes on the path
+    // to a key that is there throughout, while readers look it up.
+    let handles: Vec<_> = (0..(*NUM_THREADS).max(4))
+        .map(|t| {
+            let trie = trie.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+
+                for _ in 0..20_000 {
+                    let trie = trie.pin();
+                    if t % 2 == 0 {
+                        assert_eq!(trie.get(&key(0, 0)), Some(&0));
+                        assert!(trie.iter_prefix(&key(0, 0)[..1]).any(|&value| value == 0));
+                        continue;
+                    }
+
+                    let key = key(rng.gen_range(0..2), rng.gen_range(1..=16));
+                    if rng.gen() {
+                        trie.insert(key, 1);
+                    } else {
+                        trie.remove(&key);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let trie_ref = trie.pin();
+    assert_eq!(trie.len(), trie_ref.iter().count());
+    assert_eq!(trie_ref.get(&key(0, 0)), Some(&0));
+}
+
+#[test]
+fn skip_list_order_test() {
+    let trie = Trie::<String, u32, _, SkipListStore<String>>::with_store();
+    let trie = trie.pin();
+    let mut rng = rand::thread_rng();
+    let mut keys = vec![];
+
+    for value in 0..200 {
+        let len = rng.gen_range(1..4);
+        let key: Vec<String> = (0..len)
+            .map(|_| {
+                ["a", "b", "ab", "ba", "c"]
+                    .choose(&mut rng)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        trie.insert(key.clone(), value);
+        keys.push(key);
+    }
+
+    keys.sort();
+    keys.dedup();
+    assert_eq!(trie.keys().collect::<Vec<_>>(), keys);
+    assert!(keys.iter().all(|key| trie.get(key).is_some()));
+}
+
+/// Checks the ordered scans of a trie against a sorted map holding the
+/// same keys.
+fn check_ordered<C>()
+where
+    C: OrderedStore<u8, RandomState>,
+{
+    let trie = Trie::<u8, u32, _, C>::with_store();
+    let trie = trie.pin();
+    let mut rng = rand::thread_rng();
+    let mut model = BTreeMap::new();
+
+    for value in 0..500 {
+        let len = rng.gen_range(0..4);
+        let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0..6)).collect();
+        trie.insert(key.clone(), value);
+        model.insert(key, value);
+    }
+
+    for _ in 0..100 {
+        let len = rng.gen_range(0..4);
+        let start: Vec<u8> = (0..len).map(|_| rng.gen_range(0..6)).collect();
+        let end: Vec<u8> = (0..len).map(|_| rng.gen_range(0..6)).collect();
+
+        let expected: Vec<_> = model.range(start.clone()..).collect();
+        let actual: Vec<_> = trie.iter_from(&start).collect();
+        assert_eq!(actual.len(), expected.len());
+        assert!(actual.iter().zip(&expected).all(|(a, b)| (&a.0, a.1) == *b));
+
+        let bounds = (Bound::Excluded(&start[..]), Bound::Included(&end[..]));
+        if start < end {
+            let expected: Vec<_> = model.range::<[u8], _>(bounds).collect();
+            let actual: Vec<_> = trie.range::<[u8], _>(bounds).collect();
+            assert_eq!(actual.len(), expected.len());
+            assert!(actual.iter().zip(&expected).all(|(a, b)| (&a.0, a.1) == *b));
+        }
+    }
+}
+
+#[test]
+fn ordered_range_test() {
+    check_ordered::<SkipListStore<u8>>();
+    check_ordered::<ByteStore>();
+    check_ordered::<ArtStore>();
+}
+
+#[test]
+fn ordered_pagination_test() {
+    let trie = OrderedTrie::<String, usize>::new();
+    let trie = trie.pin();
+    let words = ["apple", "banana", "cherry", "date", "elder", "fig", "grape"];
+    for (value, word) in words.iter().enumerate() {
+        trie.insert(["fruit".to_string(), word.to_string()], value);
+    }
+    trie.insert(["veg".to_string()], 7);
+
+    // Pages of two keys, each starting after the last key seen.
+    let start = vec!["fruit".to_string()];
+    let end = vec!["fruit".to_string(), "~".to_string()];
+    let mut pages = vec![];
+    let mut page: Vec<_> = trie.range(start..end.clone()).take(2).collect();
+    while !page.is_empty() {
+        let last = page.last().unwrap().0.clone();
+        pages.push(
+            page.into_iter()
+                .map(|(_, value)| *value)
+                .collect::<Vec<_>>(),
+        );
+        let bounds = (Bound::Excluded(last), Bound::Excluded(end.clone()));
+        page = trie.range(bounds).take(2).collect();
+    }
+    assert_eq!(pages, [vec![0, 1], vec![2, 3], vec![4, 5], vec![6]]);
+
+    let keys: Vec<_> = trie.range(..=vec!["fruit".to_string()]).collect();
+    assert!(keys.is_empty());
+    assert_eq!(trie.iter_from(&["g".to_string()]).count(), 1);
+}
+
+#[test]
+fn ordered_trie_snapshot_test() {
+    let trie = OrderedTrie::<u8, u32>::default();
+    for i in (0..10u8).rev() {
+        trie.pin().insert([i % 3, i], i as u32);
+    }
+
+    // The snapshot is an ordered trie in turn.
+    let snapshot: OrderedTrie<u8, u32> = trie.snapshot();
+    trie.pin().remove(&[0, 0]);
+    let values: Vec<_> = snapshot.pin().iter().copied().collect();
+    assert_eq!(values, [0, 3, 6, 9, 1, 4, 7, 2, 5, 8]);
+    assert_eq!(trie.len(), 9);
+}
+
+#[test]
+fn byte_trie_snapshot_test() {
+    let trie = ByteTrie::<u32>::default();
+    for i in (0..10u8).rev() {
+        trie.pin().insert([i % 3, i], i as u32);
+    }
+
+    // The snapshot is a byte trie in turn.
+    let snapshot: ByteTrie<u32> = trie.snapshot();
+    trie.pin().remove(&[0, 0]);
+    let values: Vec<_> = snapshot.pin().iter().copied().collect();
+    assert_eq!(values, [0, 3, 6, 9, 1, 4, 7, 2, 5, 8]);
+    assert_eq!(trie.len(), 9);
+}
@@ -0,0 +1,265 @@
+use chash_trie::store::SkipListStore;
+use chash_trie::Trie;
+use once_cell::sync::Lazy;
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering::*},
+        Arc,
+    },
+    thread::{available_parallelism, spawn},
+};
+
+static NUM_THREADS: Lazy<usize> = Lazy::new(|| available_parallelism().unwrap().get());
+
+#[test]
+fn transaction_test() {
+    for trie in [Trie::new(), Trie::new().compressed()] {
+        let guard = trie.pin();
+        guard.insert("/tmp/a".chars(), 1);
+        guard.insert("/tmp/b".chars(), 2);
+        let key = |key: &str| key.chars().collect::<Vec<_>>();
+
+        let removed = trie.transaction(|tx| {
+            let value = tx.remove(&key("/tmp/a")).unwrap();
+            tx.insert("/home/a".chars(), value);
+            assert_eq!(tx.get(&key("/home/a")), Some(&1));
+            assert_eq!(tx.get(&key("/tmp/a")), None);
+            tx.remove(&key("/tmp/b"))
+        });
+        assert_eq!(removed, Some(2));
+
+        assert_eq!(guard.get(&key("/tmp/a")), None);
+        assert_eq!(guard.get(&key("/tmp/b")), None);
+        assert_eq!(guard.get(&key("/home/a")), Some(&1));
+        assert_eq!(trie.len(), 1);
+
+        // The trie can be written to as before.
+        guard.insert("/home/b".chars(), 2);
+        assert_eq!(guard.remove(&key("/home/a")), Some(&1));
+        assert_eq!(guard.iter().collect::<Vec<_>>(), [&2]);
+        assert_eq!(trie.len(), 1);
+    }
+}
+
+#[test]
+fn empty_trie_transaction_test() {
+    let trie = Trie::<u8, u32>::new();
+    trie.transaction(|tx| {
+        tx.insert([1], 1);
+    });
+    assert_eq!(trie.pin().get(&[1]), Some(&1));
+
+    let removed = trie.transaction(|tx| tx.remove(&[1]));
+    assert_eq!(removed, Some(1));
+    assert!(trie.is_empty());
+    assert_eq!(trie.pin().iter().count(), 0);
+}
+
+#[test]
+fn concurrent_transaction_test() {
+    let num_accounts = 100u32;
+    let num_transfers = 1000;
+    let trie = Arc::new(Trie::<u32, u32, _, SkipListStore<u32>>::with_store().compressed());
+    let done = Arc::new(AtomicBool::new(false));
+    for account in 0..num_accounts {
+        trie.pin().insert([account / 10, account], 100);
+    }
+
+    // Each transfer reads two balances, and moves one from the first to
+    // the second, so that lost updates would show in the total.
+    let writers: Vec<_> = (0..*NUM_THREADS as u32)
+        .map(|t| {
+            let trie = trie.clone();
+            spawn(move || {
+                for i in 0..num_transfers {
+                    let from = (t * 7 + i * 13) % num_accounts;
+                    let to = (from + 1 + i % 5) % num_accounts;
+                    trie.transaction(|tx| {
+                        let balance = *tx.get(&[from / 10, from]).unwrap();
+                        if balance > 0 {
+                            let other = *tx.get(&[to / 10, to]).unwrap();
+                            tx.insert([from / 10, from], balance - 1);
+                            tx.insert([to / 10, to], other + 1);
+                        }
+                    });
+                }
+            })
+        })
+        .collect();
+
+    let reader = {
+        let trie = trie.clone();
+        let done = done.clone();
+        spawn(move || {
+            while !done.load(Acquire) {
+                let guard = trie.pin();
+                assert_eq!(guard.iter().sum::<u32>(), 100 * num_accounts);
+                assert_eq!(guard.iter().count(), num_accounts as usize);
+            }
+        })
+    };
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    done.store(true, Release);
+    reader.join().unwrap();
+
+    let guard = trie.pin();
+    assert_eq!(guard.iter().sum::<u32>(), 100 * num_accounts);
+    assert_eq!(trie.len(), num_accounts as usize);
+}
+
+#[test]
+fn conflicting_writes_test() {
+    let trie = Arc::new(Trie::<u8, u32>::new());
+    trie.pin().insert([0], 0);
+
+    // Plain writes to other keys do not conflict with the transactions.
+    let writer = {
+        let trie = trie.clone();
+        spawn(move || {
+            let guard = trie.pin();
+            for i in 0..10_000u32 {
+                guard.insert([1, (i % 100) as u8], i);
+            }
+        })
+    };
+
+    let threads: Vec<_> = (0..*NUM_THREADS)
+        .map(|_| {
+            let trie = trie.clone();
+            spawn(move || {
+                for _ in 0..200 {
+                    trie.transaction(|tx| {
+                        let count = *tx.get(&[0]).unwrap();
+                        tx.insert([0], count + 1);
+                    });
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(trie.pin().get(&[0]), Some(&(200 * *NUM_THREADS as u32)));
+    assert_eq!(trie.len(), 101);
+}
+
+#[test]
+fn conflict_test() {
+    let trie = Trie::<u8, u32>::new();
+    let guard = trie.pin();
+    guard.insert([0], 0);
+    guard.insert([1], 0);
+
+    // A write to a key the transaction read makes it run again, while
+    // one to a key it only wrote does not.
+    let mut num_runs = 0;
+    trie.transaction(|tx| {
+        num_runs += 1;
+        let count = *tx.get(&[0]).unwrap();
+        if num_runs == 1 {
+            guard.insert([0], 10);
+        }
+        guard.insert([1], num_runs);
+        tx.insert([0], count + 1);
+        tx.insert([1], count + 1);
+    });
+    assert_eq!(num_runs, 2);
+    assert_eq!(guard.get(&[0]), Some(&11));
+    assert_eq!(guard.get(&[1]), Some(&11));
+}
+
+#[test]
+fn panicking_transaction_test() {
+    let trie = Trie::<u8, u32>::new();
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        trie.transaction(|tx| {
+            tx.insert([1], 1);
+            panic!("the transaction is given up");
+        })
+    }));
+    assert!(result.is_err());
+
+    // Nothing was written, and the trie can be written to as before.
+    assert!(trie.is_empty());
+    trie.transaction(|tx| tx.insert([2], 2));
+    trie.pin().insert([3], 3);
+    assert_eq!(trie.snapshot().len(), 2);
+}
+
+#[test]
+fn wide_node_transaction_test() {
+    let num_accounts = 2000u32;
+    let num_transfers = 100;
+    let trie = Arc::new(Trie::<u32, u32>::new());
+    let done = Arc::new(AtomicBool::new(false));
+    for account in 0..num_accounts {
+        trie.pin().insert([account], 1);
+    }
+
+    // The transfers move balances between the children of the root,
+    // while a writer adds more children to it, which the copies of the
+    // root made for the commits may miss.
+    let writer = {
+        let trie = trie.clone();
+        spawn(move || {
+            let guard = trie.pin();
+            for i in 0..200 {
+                guard.insert([num_accounts + i], 0);
+            }
+        })
+    };
+
+    let transferrers: Vec<_> = (0..*NUM_THREADS as u32)
+        .map(|t| {
+            let trie = trie.clone();
+            spawn(move || {
+                for i in 0..num_transfers {
+                    let from = (t * 7919 + i * 104_729) % num_accounts;
+                    let to = (from + 1 + i) % num_accounts;
+                    trie.transaction(|tx| {
+                        let balance = *tx.get(&[from]).unwrap();
+                        if balance > 0 {
+                            let other = *tx.get(&[to]).unwrap();
+                            tx.insert([from], balance - 1);
+                            tx.insert([to], other + 1);
+                        }
+                    });
+                }
+            })
+        })
+        .collect();
+
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let trie = trie.clone();
+            let done = done.clone();
+            spawn(move || {
+                while !done.load(Acquire) {
+                    let guard = trie.pin();
+                    assert_eq!(guard.iter().sum::<u32>(), num_accounts);
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for transferrer in transferrers {
+        transferrer.join().unwrap();
+    }
+    done.store(true, Release);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let guard = trie.pin();
+    assert_eq!(guard.iter().sum::<u32>(), num_accounts);
+    assert_eq!(guard.iter().count(), num_accounts as usize + 200);
+    assert_eq!(trie.len(), num_accounts as usize + 200);
+    assert_eq!(guard.count_prefix(&[]), num_accounts as usize + 200);
+}
@@ -1,8 +1,11 @@
-use chash_trie::Trie;
+use chash_trie::{Error, Iter, Operation, OrderedTrie, Retry, Trie};
 use once_cell::sync::Lazy;
 use rand::prelude::*;
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering::*},
+        Arc,
+    },
     thread::{available_parallelism, sleep, spawn},
     time::Duration,
 };
@@ -165,3 +168,735 @@ fn race_insert_get_test() {
         handle.join().unwrap();
     }
 }
+
+#[test]
+fn iter_with_keys_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert([], 0);
+    guard.insert([1u8], 1);
+    guard.insert([1, 2], 12);
+    guard.insert([1, 2, 3], 123);
+    guard.insert([2, 4], 24);
+
+    let mut pairs: Vec<_> = guard
+        .iter_with_keys()
+        .map(|(key, value)| (key, *value))
+        .collect();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            (vec![], 0),
+            (vec![1], 1),
+            (vec![1, 2], 12),
+            (vec![1, 2, 3], 123),
+            (vec![2, 4], 24),
+        ]
+    );
+
+    let mut keys: Vec<_> = guard.keys().collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        vec![vec![], vec![1], vec![1, 2], vec![1, 2, 3], vec![2, 4]]
+    );
+}
+
+#[test]
+fn iter_prefix_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert([1u8], 1);
+    guard.insert([1, 2], 12);
+    guard.insert([1, 2, 3], 123);
+    guard.insert([1, 3], 13);
+    guard.insert([2, 2], 22);
+
+    let mut values: Vec<_> = guard.iter_prefix(&[1, 2]).cloned().collect();
+    values.sort();
+    assert_eq!(values, vec![12, 123]);
+
+    assert!(guard.iter_prefix(&[3]).next().is_none());
+
+    let entry = guard.find(&[1]).unwrap();
+    let mut values: Vec<_> = entry.iter().cloned().collect();
+    values.sort();
+    assert_eq!(values, vec![1, 12, 13, 123]);
+
+    let mut pairs: Vec<_> = entry
+        .iter_with_keys()
+        .map(|(key, value)| (key, *value))
+        .collect();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![(vec![], 1), (vec![2], 12), (vec![2, 3], 123), (vec![3], 13)]
+    );
+}
+
+#[test]
+fn iter_size_hint_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+    for i in 0..100u8 {
+        guard.insert([i % 10, i], i as u32);
+    }
+    guard.insert([], 100);
+
+    // The trie may be written to while it is iterated over.
+    let mut iter: Iter<'_, _, _, _, _> = guard.iter();
+    assert_eq!(iter.size_hint(), (0, None));
+    assert!(iter.next().is_some());
+    assert_eq!(iter.size_hint(), (0, None));
+
+    // A snapshot never is.
+    let snapshot = trie.read_only_snapshot();
+    let guard = snapshot.pin();
+    let mut iter = guard.iter();
+    assert_eq!(iter.size_hint(), (101, Some(101)));
+    for len in (0..101).rev() {
+        assert!(iter.next().is_some());
+        assert_eq!(iter.size_hint(), (len, Some(len)));
+    }
+    assert!(iter.next().is_none());
+    assert!(iter.next().is_none());
+    assert_eq!(iter.size_hint(), (0, Some(0)));
+
+    let iter = guard.iter_prefix(&[3]);
+    assert_eq!(iter.size_hint(), (10, Some(10)));
+    assert_eq!(iter.sum::<u32>(), (3..100).step_by(10).sum());
+    assert_eq!(guard.iter_prefix(&[10]).size_hint(), (0, Some(0)));
+}
+
+#[test]
+fn longest_prefix_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    assert_eq!(guard.longest_prefix(&[1u8, 2, 3]), None);
+
+    guard.insert([1u8], 1);
+    guard.insert([1, 2, 3, 4], 1234);
+
+    assert_eq!(guard.longest_prefix(&[1, 2, 3]), Some((1, &1)));
+    assert_eq!(guard.longest_prefix(&[1, 2, 3, 4, 5]), Some((4, &1234)));
+    assert_eq!(guard.longest_prefix(&[2]), None);
+
+    guard.insert([], 0);
+    assert_eq!(guard.longest_prefix(&[2]), Some((0, &0)));
+}
+
+#[test]
+fn ancestors_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    assert!(guard.ancestors(&["org", "team"]).next().is_none());
+
+    guard.insert(["org"], 1);
+    guard.insert(["org", "team", "service"], 3);
+    guard.insert(["org", "team", "service", "instance"], 4);
+
+    let values: Vec<_> = guard.ancestors(&["org", "team", "service"]).collect();
+    assert_eq!(values, vec![(1, &1), (3, &3)]);
+
+    let values: Vec<_> = guard.ancestors(&["org", "other", "service"]).collect();
+    assert_eq!(values, vec![(1, &1)]);
+}
+
+#[test]
+fn entry_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    assert_eq!(*guard.entry([1u8, 2]).or_insert(12), 12);
+    assert_eq!(*guard.entry([1, 2]).or_insert(0), 12);
+    assert_eq!(*guard.entry([1, 3]).or_insert_with(|| 13), 13);
+    assert_eq!(*guard.entry([1]).or_default(), 0);
+
+    let value = guard
+        .entry([1, 2])
+        .and_modify(|value| value + 1)
+        .or_insert(0);
+    assert_eq!(*value, 13);
+    assert_eq!(guard.get(&[1, 2]), Some(&13));
+
+    let value = guard.entry([2]).and_modify(|value| value + 1).or_insert(2);
+    assert_eq!(*value, 2);
+}
+
+#[test]
+fn race_entry_or_insert_test() {
+    let trie = Arc::new(Trie::new());
+    let key = [3u8, 1, 4, 1, 5];
+
+    let inserters: Vec<_> = (0..*NUM_THREADS)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || *trie.pin().entry(key).or_insert(thread_id))
+        })
+        .collect();
+
+    let values: Vec<_> = inserters
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+    assert!(values.iter().all(|value| *value == values[0]));
+    assert_eq!(trie.pin().get(&key), Some(&values[0]));
+}
+
+#[test]
+fn concurrent_and_modify_test() {
+    let trie = Arc::new(Trie::new());
+    let num_rounds = 1000;
+    trie.pin().insert(["counter"], 0);
+
+    let writers: Vec<_> = (0..*NUM_THREADS)
+        .map(|_| {
+            let trie = trie.clone();
+            spawn(move || {
+                for _ in 0..num_rounds {
+                    trie.pin().entry(["counter"]).and_modify(|count| count + 1);
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        trie.pin().get(&["counter"]),
+        Some(&(*NUM_THREADS * num_rounds))
+    );
+}
+
+#[test]
+fn compare_and_swap_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    let error = guard.compare_and_swap(&[1u8], &0, 1).unwrap_err();
+    assert_eq!(error.current, None);
+    assert_eq!(error.new, 1);
+
+    assert_eq!(guard.insert_if_absent([1], 10), Ok(&10));
+    assert_eq!(guard.insert_if_absent([1], 11), Err((&10, 11)));
+
+    let error = guard.compare_and_swap(&[1], &0, 12).unwrap_err();
+    assert_eq!(error.current, Some(&10));
+    assert_eq!(guard.compare_and_swap(&[1], &10, 12), Ok(&10));
+    assert_eq!(guard.get(&[1]), Some(&12));
+}
+
+#[test]
+fn race_insert_if_absent_test() {
+    let trie = Arc::new(Trie::new());
+    let key = [2u8, 7, 1, 8];
+
+    let inserters: Vec<_> = (0..*NUM_THREADS)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || trie.pin().insert_if_absent(key, thread_id).is_ok())
+        })
+        .collect();
+
+    let num_winners = inserters
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|is_winner| *is_winner)
+        .count();
+    assert_eq!(num_winners, 1);
+}
+
+#[test]
+fn update_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    assert_eq!(guard.update([1u8, 2], |_| None), None);
+    assert_eq!(guard.get(&[1, 2]), None);
+
+    assert_eq!(
+        guard.update([1, 2], |value| Some(value.map_or(1, |v| v + 1))),
+        None
+    );
+    assert_eq!(
+        guard.update([1, 2], |value| Some(value.map_or(1, |v| v + 1))),
+        Some(&1)
+    );
+    assert_eq!(guard.get(&[1, 2]), Some(&2));
+
+    assert_eq!(guard.update([1, 2], |_| None), Some(&2));
+    assert_eq!(guard.get(&[1, 2]), None);
+    assert!(guard.iter().next().is_none());
+}
+
+#[test]
+fn update_to_none_prunes_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    // The key shows up behind the back of the update, which then
+    // removes it without leaving empty nodes behind.
+    let replaced = guard.update([1u8, 2], |value| match value {
+        None => {
+            guard.insert([1, 2], 5);
+            Some(1)
+        }
+        Some(_) => None,
+    });
+    assert_eq!(replaced, Some(&5));
+    assert!(guard.find(&[1]).is_none());
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn concurrent_update_test() {
+    let trie = Arc::new(Trie::new());
+    let num_rounds = 1000;
+
+    let writers: Vec<_> = (0..*NUM_THREADS)
+        .map(|_| {
+            let trie = trie.clone();
+            spawn(move || {
+                for _ in 0..num_rounds {
+                    trie.pin().update(["path", "counter"], |count| {
+                        Some(count.map_or(1, |c| c + 1))
+                    });
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        trie.pin().get(&["path", "counter"]),
+        Some(&(*NUM_THREADS * num_rounds))
+    );
+}
+
+#[test]
+fn insert_retry_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert([1u8], String::from("one"));
+    let entry = guard.find(&[1]).unwrap();
+    assert_eq!(guard.remove(&[1]).map(String::as_str), Some("one"));
+
+    let value = String::from("uno");
+    assert_eq!(entry.try_insert(value), Err(Retry(String::from("uno"))));
+    assert_eq!(guard.try_insert([1], String::from("uno")), Ok(None));
+    assert_eq!(
+        guard
+            .try_insert([1], String::from("eins"))
+            .map(|value| value.cloned()),
+        Ok(Some(String::from("uno")))
+    );
+}
+
+#[test]
+fn try_remove_error_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert([1u8, 2], 12);
+
+    let error = guard.try_remove(&[1, 3, 4]).unwrap_err();
+    assert_eq!(
+        error,
+        Error::NotFound {
+            operation: Operation::Remove,
+            depth: 1
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        "remove failed: no value found at depth 1"
+    );
+
+    assert_eq!(guard.try_remove(&[1, 2]), Ok(&12));
+}
+
+#[test]
+fn remove_prefix_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert(["tenant"], 0);
+    guard.insert(["tenant", "42"], 42);
+    guard.insert(["tenant", "42", "session", "a"], 1);
+    guard.insert(["tenant", "42", "session", "b"], 2);
+    guard.insert(["tenant", "43", "session", "a"], 3);
+
+    assert_eq!(guard.remove_prefix(&["tenant", "44"]), 0);
+    assert_eq!(guard.remove_prefix(&["tenant", "42"]), 3);
+    assert_eq!(guard.get(&["tenant", "42", "session", "a"]), None);
+    assert!(guard.find(&["tenant", "42"]).is_none());
+
+    let mut values: Vec<_> = guard.iter().cloned().collect();
+    values.sort();
+    assert_eq!(values, vec![0, 3]);
+
+    guard.insert(["tenant", "42", "session", "c"], 4);
+    assert_eq!(guard.get(&["tenant", "42", "session", "c"]), Some(&4));
+
+    assert_eq!(guard.remove_prefix(&[] as &[&str]), 3);
+    assert!(guard.iter().next().is_none());
+}
+
+#[test]
+fn entry_navigation_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert(["org", "team", "a"], 1);
+    guard.insert(["org", "team", "b"], 2);
+    guard.insert(["org", "other"], 3);
+
+    let team = guard.find(&["org", "team"]).unwrap();
+    assert_eq!(team.depth(), 2);
+    assert_eq!(team.key(), Some(vec!["org", "team"]));
+
+    let mut children: Vec<_> = team
+        .children()
+        .map(|(seg, child)| (seg, child.depth(), child.get().cloned()))
+        .collect();
+    children.sort();
+    assert_eq!(children, vec![("a", 3, Some(1)), ("b", 3, Some(2))]);
+
+    let (_, child) = team.children().next().unwrap();
+    assert_eq!(child.parent().unwrap().key(), Some(vec!["org", "team"]));
+
+    let org = team.parent().unwrap();
+    assert_eq!(org.key(), Some(vec!["org"]));
+    assert_eq!(org.children().count(), 2);
+
+    let root = org.parent().unwrap();
+    assert_eq!(root.depth(), 0);
+    assert_eq!(root.key(), Some(vec![]));
+    assert!(root.parent().is_none());
+
+    let other = org.child(&"other").unwrap();
+    assert_eq!(other.get(), Some(&3));
+    assert_eq!(other.key(), Some(vec!["org", "other"]));
+}
+
+#[test]
+fn entry_write_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    guard.insert(["tenant", "42"], 0);
+    let tenant = guard.find(&["tenant", "42"]).unwrap();
+
+    assert_eq!(tenant.insert_child("a", 1), Ok(None));
+    assert_eq!(tenant.insert_at(["b", "c"], 2), Ok(None));
+    assert_eq!(tenant.insert_at(["b", "c"], 3), Ok(Some(&2)));
+    assert_eq!(guard.get(&["tenant", "42", "a"]), Some(&1));
+    assert_eq!(guard.get(&["tenant", "42", "b", "c"]), Some(&3));
+
+    let c = tenant.find(&["b", "c"]).unwrap();
+    assert_eq!(c.remove(), Some(&3));
+    assert_eq!(c.remove(), None);
+    assert!(c.is_removed());
+    assert_eq!(c.key(), None);
+    assert!(tenant.child(&"b").is_none());
+
+    let a = tenant.child(&"a").unwrap();
+    assert_eq!(a.remove(), Some(&1));
+    assert_eq!(tenant.remove(), Some(&0));
+    assert!(tenant.is_removed());
+    assert!(guard.iter().next().is_none());
+    assert!(guard.find(&["tenant"]).is_none());
+    assert_eq!(tenant.insert_child("a", 4), Err(Retry(4)));
+}
+
+#[test]
+fn len_test() {
+    let trie = Trie::new();
+    assert!(trie.is_empty());
+
+    {
+        let guard = trie.pin();
+        guard.insert([1u8], 1);
+        guard.insert([1], 2);
+        guard.insert([1, 2], 12);
+        guard.insert([1, 2, 3], 123);
+        let _ = guard.insert_if_absent([1, 2], 0);
+        guard.update([2], |_| Some(2));
+    }
+    assert_eq!(trie.len(), 4);
+
+    {
+        let guard = trie.pin();
+        guard.remove(&[1]);
+        guard.remove(&[1]);
+        guard.update([2], |_| None);
+    }
+    assert_eq!(trie.len(), 2);
+
+    trie.pin().remove_prefix(&[1]);
+    assert_eq!(trie.len(), 0);
+    assert!(trie.is_empty());
+}
+
+#[test]
+fn concurrent_len_test() {
+    let trie = Arc::new(Trie::new());
+    let num_rounds = 1000;
+
+    let writers: Vec<_> = (0..*NUM_THREADS)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || {
+                for round in 0..num_rounds {
+                    trie.pin().insert([thread_id, round % 10, round], round);
+                }
+                for round in (0..num_rounds).step_by(2) {
+                    trie.pin().remove(&[thread_id, round % 10, round]);
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(trie.len(), *NUM_THREADS * num_rounds / 2);
+    assert_eq!(trie.len(), trie.pin().iter().count());
+}
+
+#[test]
+fn racing_len_test() {
+    let trie = Arc::new(Trie::new());
+    let done = Arc::new(AtomicBool::new(false));
+    let num_keys = 4u8;
+    let num_writers = 2 * *NUM_THREADS;
+
+    // Removers race the inserters for the same keys, so a value may be
+    // taken as soon as it shows up.
+    let writers: Vec<_> = (0..num_writers)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || {
+                for round in 0..10_000 {
+                    let key = [round as u8 % num_keys];
+                    match thread_id % 2 {
+                        0 => trie.pin().insert(key, round),
+                        _ => trie.pin().remove(&key),
+                    };
+                }
+            })
+        })
+        .collect();
+
+    let watcher = {
+        let trie = trie.clone();
+        let done = done.clone();
+        spawn(move || {
+            while !done.load(Acquire) {
+                assert!(trie.len() <= num_keys as usize + num_writers);
+            }
+        })
+    };
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+    done.store(true, Release);
+    watcher.join().unwrap();
+    assert_eq!(trie.len(), trie.pin().iter().count());
+}
+
+#[test]
+fn count_prefix_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+    assert_eq!(guard.count_prefix(&[] as &[u8]), 0);
+
+    guard.insert([1u8], 1);
+    guard.insert([1, 2], 12);
+    guard.insert([1, 2, 3], 123);
+    guard.insert([1, 3], 13);
+    guard.insert([2], 2);
+    guard.insert([1, 2], 0);
+
+    assert_eq!(guard.count_prefix(&[] as &[u8]), 5);
+    assert_eq!(guard.count_prefix(&[1]), 4);
+    assert_eq!(guard.count_prefix(&[1, 2]), 2);
+    assert_eq!(guard.count_prefix(&[1, 2, 3]), 1);
+    assert_eq!(guard.count_prefix(&[3]), 0);
+
+    guard.remove(&[1, 2]);
+    guard.update([1, 3], |_| None);
+    guard.update([1, 4], |_| Some(14));
+    assert_eq!(guard.count_prefix(&[1]), 3);
+    assert_eq!(guard.count_prefix(&[1, 2]), 1);
+
+    let entry = guard.find(&[1]).unwrap();
+    entry.insert_child(5, 15).unwrap();
+    assert_eq!(guard.count_prefix(&[1]), 4);
+    entry.remove();
+    assert_eq!(guard.count_prefix(&[1]), 3);
+
+    assert_eq!(guard.remove_prefix(&[1, 2]), 1);
+    assert_eq!(guard.count_prefix(&[1]), 2);
+    assert_eq!(guard.count_prefix(&[] as &[u8]), 3);
+}
+
+#[test]
+fn concurrent_count_prefix_test() {
+    let trie = Arc::new(Trie::new());
+    let num_rounds = 1000;
+
+    let writers: Vec<_> = (0..*NUM_THREADS)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || {
+                for round in 0..num_rounds {
+                    trie.pin().insert([round % 4, thread_id, round], round);
+                }
+                for round in (0..num_rounds).step_by(2) {
+                    trie.pin().remove(&[round % 4, thread_id, round]);
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+
+    let guard = trie.pin();
+    for seg in 0..4 {
+        assert_eq!(
+            guard.count_prefix(&[seg]),
+            guard.iter_prefix(&[seg]).count()
+        );
+    }
+    assert_eq!(guard.count_prefix(&[] as &[usize]), trie.len());
+}
+
+#[test]
+fn concurrent_remove_and_get_test() {
+    let trie = Arc::new(Trie::new());
+    let num_rounds = 1000;
+    let num_threads = (*NUM_THREADS).max(2);
+
+    // Writers keep emptying and refilling the same short paths, so
+    // nodes get removed and replaced under the readers.
+    let handles: Vec<_> = (0..num_threads)
+        .map(|thread_id| {
+            let trie = trie.clone();
+            spawn(move || {
+                let mut rng = rand::thread_rng();
+
+                for _ in 0..num_rounds {
+                    let key = [rng.gen_range(0..4u8), rng.gen_range(0..4u8)];
+
+                    if thread_id % 2 == 0 {
+                        let guard = trie.pin();
+                        guard.insert(key, key[0] * 4 + key[1]);
+                        guard.remove(&key[..1]);
+                        guard.remove(&key);
+                    } else {
+                        let guard = trie.pin();
+                        if let Some(&value) = guard.get(&key) {
+                            assert_eq!(value, key[0] * 4 + key[1]);
+                        }
+                        guard.find(&key[..1]);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(trie.is_empty());
+    assert_eq!(trie.pin().iter().count(), 0);
+}
+
+#[test]
+fn wide_fanout_test() {
+    let trie = Trie::new();
+    let guard = trie.pin();
+
+    // Grow a node well past a handful of children, then shrink it
+    // back, checking the lookups on the way.
+    for i in 0..100u32 {
+        guard.insert([0, i], i);
+    }
+    for i in 0..100u32 {
+        assert_eq!(guard.get(&[0, i]), Some(&i));
+    }
+    assert_eq!(guard.count_prefix(&[0]), 100);
+
+    for i in 3..100u32 {
+        assert_eq!(guard.remove(&[0, i]), Some(&i));
+    }
+    let mut keys: Vec<_> = guard.keys().collect();
+    keys.sort();
+    assert_eq!(keys, vec![vec![0, 0], vec![0, 1], vec![0, 2]]);
+
+    for i in 0..20u32 {
+        guard.insert([0, i], i + 1);
+    }
+    assert_eq!(guard.iter_prefix(&[0]).count(), 20);
+    assert_eq!(guard.get(&[0, 19]), Some(&20));
+    assert_eq!(guard.get(&[0, 20]), None);
+}
+
+#[test]
+fn deep_key_test() {
+    // Far deeper than the stack could take with a frame per segment.
+    let depth = 200_000;
+    let key: Vec<u8> = (0..depth).map(|i| (i % 3) as u8).collect();
+    let trie = Trie::new();
+
+    {
+        let trie = trie.pin();
+        trie.insert(key.clone(), 2);
+        trie.insert(key[..depth / 2].to_vec(), 1);
+        trie.insert([1u8], 0);
+
+        assert_eq!(trie.get(&key), Some(&2));
+        let entry = trie.find(&key).unwrap();
+        assert_eq!(entry.key(), Some(key.clone()));
+        assert_eq!(trie.longest_prefix(&key), Some((depth, &2)));
+        assert_eq!(trie.ancestors(&key).count(), 2);
+        assert_eq!(trie.count_prefix(&key[..10]), 2);
+        assert_eq!(trie.iter().count(), 3);
+        assert_eq!(trie.keys().map(|key| key.len()).max(), Some(depth));
+
+        assert_eq!(trie.remove(&key), Some(&2));
+        assert_eq!(trie.get(&key[..depth / 2]), Some(&1));
+        assert_eq!(trie.remove_prefix(&key[..10]), 1);
+        trie.insert(key.clone(), 3);
+    }
+
+    // Dropping the trie frees the whole chain.
+    drop(trie);
+
+    let trie = OrderedTrie::new();
+    let trie = trie.pin();
+    trie.insert(key.clone(), 2);
+    trie.insert([2u8], 1);
+    let keys: Vec<_> = trie
+        .iter_from(&key[..depth / 2])
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, [key, vec![2]]);
+}